@@ -1,8 +1,15 @@
-use crate::domain::models::CommandError;
+use crate::domain::models::{AlreadyProcessed, CancelToken, CommandError};
 use crate::domain::services::{BaseActions, CryptoService, FileService, TerminalService};
 
+use super::file_start::{FileStart, read_file_start};
+use super::revert::revert_on_err;
+
 pub struct DecryptUseCase<F, C, T> {
     counter: usize,
+    on_already: AlreadyProcessed,
+    skipped: bool,
+    allow_legacy: bool,
+    cancel: CancelToken,
     file_service: F,
     crypto_service: C,
     terminal: T,
@@ -17,12 +24,44 @@ where
     pub fn new(file_service: F, crypto_service: C, terminal: T) -> Self {
         Self {
             counter: 0,
+            on_already: AlreadyProcessed::Force,
+            skipped: false,
+            allow_legacy: false,
+            cancel: CancelToken::default(),
             file_service,
             crypto_service,
             terminal,
         }
     }
 
+    /// Что делать с незашифрованным файлом. Заголовок читается до создания
+    /// временного файла; с `Force` (по умолчанию) незашифрованный файл
+    /// не пропускается, а приводит к ошибке разбора заголовка.
+    pub fn on_already(mut self, policy: AlreadyProcessed) -> Self {
+        self.on_already = policy;
+        self
+    }
+
+    /// Расшифровывать ли файл устаревшего формата. В его заголовке нет проверки
+    /// ключа, поэтому с неверным ключом результат — мусор, который без
+    /// разрешения не должен заменять исходный файл.
+    pub fn allow_legacy(mut self, allow: bool) -> Self {
+        self.allow_legacy = allow;
+        self
+    }
+
+    /// Токен отмены: после сигнала временный файл удаляется,
+    /// а исходный файл остаётся нетронутым
+    pub fn with_cancel(mut self, cancel: CancelToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Был ли файл пропущен по политике `AlreadyProcessed::Skip`
+    pub fn is_skipped(&self) -> bool {
+        self.skipped
+    }
+
     fn show_counter(&mut self) {
         self.counter += 1;
         self.terminal
@@ -40,38 +79,83 @@ where
     fn execute(&mut self) -> Result<(), Self::Error> {
         self.file_service
             .init_original()
-            .and_then(FileService::make_temp)
+            .map_err(CommandError::FileServiceError)?;
+
+        let start = read_file_start(&mut self.file_service, |head| {
+            self.crypto_service.probe_header(head)
+        })?;
+        let header = match start {
+            FileStart::Header(header) => header,
+            FileStart::Legacy(_) if !self.allow_legacy => {
+                return Err(CommandError::UncheckedLegacy);
+            }
+            FileStart::Legacy(header) => header,
+            FileStart::Plain(head) => match self.on_already {
+                AlreadyProcessed::Skip => {
+                    self.skipped = true;
+                    self.terminal
+                        .print_msg("Файл не зашифрован, пропущен".to_string());
+                    return Ok(());
+                }
+                AlreadyProcessed::Error => return Err(CommandError::NotEncrypted),
+                AlreadyProcessed::Force => head,
+            },
+        };
+        self.crypto_service
+            .init_decrypt(&header)
+            .map_err(CommandError::CryptoService)?;
+
+        self.file_service
+            .make_temp()
             .map_err(CommandError::FileServiceError)?;
 
         loop {
-            let chunk = self.file_service.read_chunk_original();
-            if chunk.is_empty() {
+            revert_on_err(&mut self.file_service, self.cancel.check())?;
+
+            let result = self
+                .file_service
+                .read_chunk_original()
+                .map_err(CommandError::FileServiceError);
+            let chunk = revert_on_err(&mut self.file_service, result)?;
+            let Some(chunk) = chunk else {
                 break;
-            }
+            };
 
             self.show_counter();
 
-            let encrypted = self
-                .crypto_service
-                .decrypt(chunk)
-                .map_err(CommandError::CryptoService)
-                .map_err(|e| match self.file_service.revert() {
-                    Ok(_) => e,
-                    Err(err) => CommandError::FileServiceError(err),
-                })?;
+            let encrypted = revert_on_err(
+                &mut self.file_service,
+                self.crypto_service
+                    .decrypt(chunk)
+                    .map_err(CommandError::CryptoService),
+            )?;
 
-            self.file_service
+            let result = self
+                .file_service
                 .write_chunk(encrypted)
-                .map_err(CommandError::FileServiceError)
-                .map_err(|e| match self.file_service.revert() {
-                    Ok(_) => e,
-                    Err(err) => CommandError::FileServiceError(err),
-                })?;
+                .map_err(CommandError::FileServiceError);
+            revert_on_err(&mut self.file_service, result)?;
         }
 
+        let last_chunk = revert_on_err(
+            &mut self.file_service,
+            self.crypto_service
+                .finish()
+                .map_err(CommandError::CryptoService),
+        )?;
+
+        if !last_chunk.is_empty() {
+            let result = self
+                .file_service
+                .write_chunk(last_chunk)
+                .map_err(CommandError::FileServiceError);
+            revert_on_err(&mut self.file_service, result)?;
+        }
+
+        revert_on_err(&mut self.file_service, self.cancel.check())?;
+
         self.file_service
-            .delete_original()
-            .and_then(FileService::rename_temp_as_original)
+            .commit()
             .map_err(CommandError::FileServiceError)?;
         Ok(())
     }
@@ -99,14 +183,14 @@ mod tests {
 
         assert!(result.is_ok());
         assert_eq!(command_called[0], "init_original");
-        assert_eq!(command_called[1], "make_temp");
-        assert_eq!(command_called[2], "read_chunk_original");
-        assert_eq!(command_called[3], "write_chunk");
-        assert_eq!(command_called[4], "read_chunk_original");
-        assert_eq!(command_called[5], "write_chunk");
-        assert_eq!(command_called[6], "read_chunk_original");
-        assert_eq!(command_called[7], "delete_original");
-        assert_eq!(command_called[8], "rename_temp_as_original");
+        assert_eq!(command_called[1], "read_exact_original");
+        assert_eq!(command_called[2], "make_temp");
+        assert_eq!(command_called[3], "read_chunk_original");
+        assert_eq!(command_called[4], "write_chunk");
+        assert_eq!(command_called[5], "read_chunk_original");
+        assert_eq!(command_called[6], "write_chunk");
+        assert_eq!(command_called[7], "read_chunk_original");
+        assert_eq!(command_called[8], "commit");
         assert_eq!(write_chunks[0], decrypt_chunk[0]);
     }
 
@@ -129,9 +213,10 @@ mod tests {
 
         assert!(result.is_err());
         assert_eq!(command_called[0], "init_original");
-        assert_eq!(command_called[1], "make_temp");
-        assert_eq!(command_called[2], "read_chunk_original");
-        assert_eq!(command_called[3], "revert");
+        assert_eq!(command_called[1], "read_exact_original");
+        assert_eq!(command_called[2], "make_temp");
+        assert_eq!(command_called[3], "read_chunk_original");
+        assert_eq!(command_called[4], "revert");
     }
 
     #[test]
@@ -152,9 +237,153 @@ mod tests {
 
         assert!(result.is_err());
         assert_eq!(command_called[0], "init_original");
-        assert_eq!(command_called[1], "make_temp");
-        assert_eq!(command_called[2], "read_chunk_original");
-        assert_eq!(command_called[3], "write_chunk");
-        assert_eq!(command_called[4], "revert");
+        assert_eq!(command_called[1], "read_exact_original");
+        assert_eq!(command_called[2], "make_temp");
+        assert_eq!(command_called[3], "read_chunk_original");
+        assert_eq!(command_called[4], "write_chunk");
+        assert_eq!(command_called[5], "revert");
+    }
+
+    //Проверяем, что при ошибке проверки целостности оригинал не заменяется
+    #[test]
+    fn finish_error() {
+        let mut file_service = MockedFileService::new();
+        file_service.read_chunks = vec![b"encrypt_abc".to_vec()];
+        let decrypt_chunk = vec![b"abc".to_vec()];
+
+        let mut crypto_service = MockCryptoService::new();
+        crypto_service.decrypt_chunk = decrypt_chunk.clone();
+        crypto_service.ok_finish = false;
+
+        let mut use_case = DecryptUseCase::new(file_service, crypto_service, MockTerminal);
+
+        let result = use_case.execute();
+
+        let command_called = use_case.file_service.called_method;
+
+        assert!(result.is_err());
+        assert_eq!(command_called[0], "init_original");
+        assert_eq!(command_called[1], "read_exact_original");
+        assert_eq!(command_called[2], "make_temp");
+        assert_eq!(command_called[3], "read_chunk_original");
+        assert_eq!(command_called[4], "write_chunk");
+        assert_eq!(command_called[5], "read_chunk_original");
+        assert_eq!(command_called[6], "revert");
+        assert!(!command_called.contains(&"commit"));
+    }
+
+    //Проверяем, что незашифрованный файл не трогается
+    #[test]
+    fn test_skip_plain() {
+        let mut file_service = MockedFileService::new();
+        file_service.read_chunks = vec![b"abc".to_vec()];
+        let mut crypto_service = MockCryptoService::new();
+        crypto_service.is_encrypt = false;
+
+        let mut use_case = DecryptUseCase::new(file_service, crypto_service, MockTerminal)
+            .on_already(AlreadyProcessed::Skip);
+        let result = use_case.execute();
+
+        assert!(result.is_ok());
+        assert!(use_case.is_skipped());
+        assert_eq!(
+            use_case.file_service.called_method,
+            vec!["init_original", "read_exact_original"]
+        );
+    }
+
+    //Проверяем, что после отмены временный файл удаляется, а исходный не заменяется
+    #[test]
+    fn test_cancelled() {
+        let mut file_service = MockedFileService::new();
+        file_service.read_chunks = vec![b"encrypt_abc".to_vec()];
+        let cancel = CancelToken::default();
+        cancel.cancel();
+
+        let mut use_case =
+            DecryptUseCase::new(file_service, MockCryptoService::new(), MockTerminal)
+                .with_cancel(cancel);
+        let result = use_case.execute();
+
+        assert!(matches!(result, Err(CommandError::Cancelled)));
+        assert_eq!(
+            use_case.file_service.called_method,
+            vec![
+                "init_original",
+                "read_exact_original",
+                "make_temp",
+                "revert"
+            ]
+        );
+    }
+
+    //Проверяем, что ошибка чтения посреди файла не принимается за его конец:
+    //временный файл удаляется, а исходный не заменяется
+    #[test]
+    fn test_read_error() {
+        let mut file_service = MockedFileService::new();
+        file_service.read_chunks = vec![b"encrypt_abc".to_vec()];
+        file_service.ok_read_chunk = false;
+        let mut crypto_service = MockCryptoService::new();
+        crypto_service.decrypt_chunk = vec![b"abc".to_vec()];
+
+        let mut use_case = DecryptUseCase::new(file_service, crypto_service, MockTerminal);
+        let result = use_case.execute();
+
+        assert!(matches!(result, Err(CommandError::FileServiceError(_))));
+        assert_eq!(
+            use_case.file_service.called_method,
+            vec![
+                "init_original",
+                "read_exact_original",
+                "make_temp",
+                "read_chunk_original",
+                "write_chunk",
+                "read_chunk_original",
+                "revert"
+            ]
+        );
+    }
+
+    //Проверяем, что с политикой Error незашифрованный файл сразу даёт ошибку
+    #[test]
+    fn test_not_encrypted_error() {
+        let mut file_service = MockedFileService::new();
+        file_service.read_chunks = vec![b"abc".to_vec()];
+        let mut crypto_service = MockCryptoService::new();
+        crypto_service.is_encrypt = false;
+
+        let mut use_case = DecryptUseCase::new(file_service, crypto_service, MockTerminal)
+            .on_already(AlreadyProcessed::Error);
+        let result = use_case.execute();
+
+        assert!(matches!(result, Err(CommandError::NotEncrypted)));
+        assert!(!use_case.file_service.called_method.contains(&"make_temp"));
+    }
+
+    //Проверяем, что файл устаревшего формата без разрешения не расшифровывается
+    //на месте, а с разрешением расшифровывается
+    #[test]
+    fn test_legacy_needs_permission() {
+        for allow in [false, true] {
+            let mut file_service = MockedFileService::new();
+            file_service.read_chunks = vec![b"encrypt_abc".to_vec()];
+            let mut crypto_service = MockCryptoService::new();
+            crypto_service.is_legacy = true;
+            crypto_service.decrypt_chunk = vec![b"abc".to_vec()];
+
+            let mut use_case =
+                DecryptUseCase::new(file_service, crypto_service, MockTerminal).allow_legacy(allow);
+            let result = use_case.execute();
+
+            let called = use_case.file_service.called_method;
+            if allow {
+                assert!(result.is_ok());
+                assert!(called.contains(&"commit"));
+            } else {
+                assert!(matches!(result, Err(CommandError::UncheckedLegacy)));
+                assert!(!called.contains(&"make_temp"));
+            }
+        }
     }
 }
@@ -0,0 +1,101 @@
+use crate::domain::models::{CommandError, HeaderProbe};
+use crate::domain::services::FileService;
+
+/// Начало файла, прочитанное до потоковой обработки
+pub enum FileStart {
+    /// Заголовок зашифрованного файла целиком, без байтов тела
+    Header(Vec<u8>),
+    /// Заголовок устаревшего формата целиком. Файл может оказаться
+    /// и незашифрованным, если его данные начинаются с того же magic
+    Legacy(Vec<u8>),
+    /// Файл не зашифрован: прочитанные байты — начало данных файла
+    Plain(Vec<u8>),
+}
+
+/// Читает заголовок отдельным шагом: `probe` по уже прочитанному началу
+/// сообщает, сколько байт заголовка не хватает, и ровно столько читается
+/// из файла. Поэтому разбор заголовка не зависит от размера части.
+///
+/// Файл, который закончился раньше заголовка, считается незашифрованным.
+pub fn read_file_start<F, E>(
+    file_service: &mut F,
+    probe: impl Fn(&[u8]) -> Result<HeaderProbe, E>,
+) -> Result<FileStart, CommandError<F::Error, E>>
+where
+    F: FileService,
+{
+    let mut head = Vec::new();
+    loop {
+        match probe(&head).map_err(CommandError::CryptoService)? {
+            HeaderProbe::Plain => return Ok(FileStart::Plain(head)),
+            HeaderProbe::Complete => return Ok(FileStart::Header(head)),
+            HeaderProbe::Legacy => return Ok(FileStart::Legacy(head)),
+            HeaderProbe::Incomplete(len) => {
+                let part = file_service
+                    .read_exact_original(len)
+                    .map_err(CommandError::FileServiceError)?;
+                let is_eof = part.len() < len;
+                head.extend(part);
+                if is_eof {
+                    return Ok(FileStart::Plain(head));
+                }
+            }
+        }
+    }
+}
+
+impl FileStart {
+    /// Заголовок файла любого формата
+    pub fn header(self) -> Option<Vec<u8>> {
+        match self {
+            FileStart::Header(header) | FileStart::Legacy(header) => Some(header),
+            FileStart::Plain(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mock_service::{MockError, MockedFileService};
+    use super::*;
+
+    /// Заголовок из 8 байт, начинающийся с `HEAD`
+    fn probe(head: &[u8]) -> Result<HeaderProbe, MockError> {
+        let len = head.len().min(4);
+        Ok(match head {
+            _ if head[..len] != b"HEAD"[..len] => HeaderProbe::Plain,
+            _ if head.len() < 4 => HeaderProbe::Incomplete(4 - head.len()),
+            _ if head.len() < 8 => HeaderProbe::Incomplete(8 - head.len()),
+            _ => HeaderProbe::Complete,
+        })
+    }
+
+    //Проверяем, что заголовок собирается из частей по байту и не захватывает тело
+    #[test]
+    fn test_header_across_chunks() {
+        let mut file_service = MockedFileService::new();
+        file_service.read_chunks = b"HEAD1234body".iter().map(|b| vec![*b]).collect();
+
+        let start = read_file_start(&mut file_service, probe);
+
+        assert!(matches!(start, Ok(FileStart::Header(header)) if header == b"HEAD1234"));
+        assert_eq!(file_service.read_chunks.len(), 4);
+    }
+
+    //Проверяем, что прочитанное начало незашифрованного и короткого файла не теряется
+    #[test]
+    fn test_plain() {
+        for data in [&b"plain text"[..], b"HEA", b""] {
+            let mut file_service = MockedFileService::new();
+            file_service.read_chunks = vec![data.to_vec()];
+
+            let start = read_file_start(&mut file_service, probe);
+            let rest = file_service.read_chunks.concat();
+
+            assert!(
+                matches!(start, Ok(FileStart::Plain(head)) if [&head[..], &rest[..]].concat() == data),
+                "{data:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,307 @@
+use super::file_start::{FileStart, read_file_start};
+use crate::domain::models::{CommandError, TempStatus};
+use crate::domain::services::{
+    BaseActions, ConsoleError, CryptoService, FileService, TerminalService,
+};
+
+/// Определяет, успела ли прерванная операция полностью записать временный файл.
+///
+/// Зашифрованный временный файл расшифровывается целиком: проверка целостности
+/// последней части показывает, что он не усечён. Ошибка расшифровки возвращается
+/// как есть. Расшифрованный временный файл сравнивается с расшифровкой исходного.
+pub struct InspectTempUseCase<F, C, T> {
+    status: TempStatus,
+    temp_file: F,
+    original_file: Option<F>,
+    temp_crypto: C,
+    original_crypto: C,
+    terminal: T,
+}
+
+impl<F, C, T> InspectTempUseCase<F, C, T>
+where
+    F: FileService,
+    C: CryptoService,
+    T: TerminalService,
+{
+    pub fn new(
+        temp_file: F,
+        original_file: Option<F>,
+        temp_crypto: C,
+        original_crypto: C,
+        terminal: T,
+    ) -> Self {
+        Self {
+            status: TempStatus::Unknown,
+            temp_file,
+            original_file,
+            temp_crypto,
+            original_crypto,
+            terminal,
+        }
+    }
+
+    pub fn status(&self) -> TempStatus {
+        self.status
+    }
+
+    fn verify_encrypted(
+        &mut self,
+        header: Vec<u8>,
+    ) -> Result<TempStatus, CommandError<F::Error, C::Error>> {
+        self.temp_crypto
+            .init_decrypt(&header)
+            .map_err(CommandError::CryptoService)?;
+
+        while let Some(chunk) = self
+            .temp_file
+            .read_chunk_original()
+            .map_err(CommandError::FileServiceError)?
+        {
+            self.temp_crypto
+                .decrypt(chunk)
+                .map_err(CommandError::CryptoService)?;
+        }
+
+        self.temp_crypto
+            .finish()
+            .map_err(CommandError::CryptoService)?;
+        Ok(TempStatus::Complete)
+    }
+
+    /// Сверяет данные временного файла с расшифровкой исходного. Совпавшее
+    /// начало означает оборванную расшифровку, полное совпадение — завершённую.
+    fn compare_with_original(
+        &mut self,
+        mut temp_data: Vec<u8>,
+    ) -> Result<TempStatus, CommandError<F::Error, C::Error>> {
+        let Some(original_file) = self.original_file.as_mut() else {
+            return Ok(TempStatus::Unknown);
+        };
+        original_file
+            .init_original()
+            .map_err(CommandError::FileServiceError)?;
+
+        let start = read_file_start(original_file, |head| {
+            self.original_crypto.probe_header(head)
+        })?;
+        let Some(header) = start.header() else {
+            return Ok(TempStatus::Unknown);
+        };
+        self.original_crypto
+            .init_decrypt(&header)
+            .map_err(CommandError::CryptoService)?;
+
+        let mut original_chunk = original_file
+            .read_chunk_original()
+            .map_err(CommandError::FileServiceError)?;
+
+        let mut clear_data = Vec::new();
+        let mut original_done = false;
+        loop {
+            if temp_data.is_empty() {
+                temp_data = self
+                    .temp_file
+                    .read_chunk_original()
+                    .map_err(CommandError::FileServiceError)?
+                    .unwrap_or_default();
+            }
+
+            if clear_data.is_empty() && !original_done {
+                clear_data = match original_chunk {
+                    Some(chunk) => self.original_crypto.decrypt(chunk),
+                    None => {
+                        original_done = true;
+                        self.original_crypto.finish()
+                    }
+                }
+                .map_err(CommandError::CryptoService)?;
+                original_chunk = original_file
+                    .read_chunk_original()
+                    .map_err(CommandError::FileServiceError)?;
+                continue;
+            }
+
+            if temp_data.is_empty() {
+                return Ok(match clear_data.is_empty() {
+                    true => TempStatus::Complete,
+                    false => TempStatus::Partial,
+                });
+            }
+
+            let len = clear_data.len().min(temp_data.len());
+            if len == 0 || clear_data[..len] != temp_data[..len] {
+                return Ok(TempStatus::Unknown);
+            }
+            clear_data.drain(..len);
+            temp_data.drain(..len);
+        }
+    }
+}
+
+impl<F, C, T> BaseActions for InspectTempUseCase<F, C, T>
+where
+    F: FileService,
+    C: CryptoService,
+    T: TerminalService,
+{
+    type Error = CommandError<F::Error, C::Error>;
+    fn execute(&mut self) -> Result<(), Self::Error> {
+        self.temp_file
+            .init_original()
+            .map_err(CommandError::FileServiceError)?;
+
+        let start = read_file_start(&mut self.temp_file, |head| {
+            self.temp_crypto.probe_header(head)
+        })?;
+        let temp_data = match start {
+            FileStart::Header(header) | FileStart::Legacy(header) => {
+                self.status = self.verify_encrypted(header)?;
+                return Ok(());
+            }
+            FileStart::Plain(head) => head,
+        };
+
+        self.status = match self.compare_with_original(temp_data) {
+            Ok(status) => status,
+            Err(CommandError::CryptoService(e)) => {
+                self.terminal.print_error_msg(format!(
+                    "Не удалось расшифровать исходный файл: {}",
+                    e.consol_log()
+                ));
+                TempStatus::Unknown
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mock_service::{MockCryptoService, MockTerminal, MockedFileService};
+    use super::*;
+
+    fn make_files(
+        temp: &[&[u8]],
+        original: &[&[u8]],
+    ) -> (MockedFileService<'static>, MockedFileService<'static>) {
+        let mut temp_file = MockedFileService::new();
+        temp_file.read_chunks = temp.iter().map(|c| c.to_vec()).collect();
+        let mut original_file = MockedFileService::new();
+        original_file.read_chunks = original.iter().map(|c| c.to_vec()).collect();
+        (temp_file, original_file)
+    }
+
+    fn plain_temp_crypto() -> MockCryptoService {
+        let mut crypto = MockCryptoService::new();
+        crypto.is_encrypt = false;
+        crypto
+    }
+
+    fn original_crypto(clear: &[u8]) -> MockCryptoService {
+        let mut crypto = MockCryptoService::new();
+        crypto.decrypt_chunk = vec![clear.to_vec()];
+        crypto
+    }
+
+    //Проверяем, что зашифрованный временный файл, прошедший проверку целостности, считается полным
+    #[test]
+    fn test_encrypted_complete() {
+        let (temp_file, _) = make_files(&[b"encrypt_abc"], &[]);
+        let mut crypto = MockCryptoService::new();
+        crypto.decrypt_chunk = vec![b"abc".to_vec()];
+
+        let mut use_case = InspectTempUseCase::new(
+            temp_file,
+            None,
+            crypto,
+            MockCryptoService::new(),
+            MockTerminal,
+        );
+        let result = use_case.execute();
+
+        assert!(result.is_ok());
+        assert_eq!(use_case.status(), TempStatus::Complete);
+    }
+
+    //Проверяем, что ошибка проверки целостности временного файла возвращается вызывающему
+    #[test]
+    fn test_encrypted_truncated() {
+        let (temp_file, _) = make_files(&[b"encrypt_abc"], &[]);
+        let mut crypto = MockCryptoService::new();
+        crypto.decrypt_chunk = vec![b"abc".to_vec()];
+        crypto.ok_finish = false;
+
+        let mut use_case = InspectTempUseCase::new(
+            temp_file,
+            None,
+            crypto,
+            MockCryptoService::new(),
+            MockTerminal,
+        );
+
+        assert!(use_case.execute().is_err());
+    }
+
+    //Проверяем, что полная расшифровка исходного файла считается завершённой
+    #[test]
+    fn test_decrypted_complete() {
+        let (temp_file, original_file) = make_files(&[b"ab", b"c"], &[b"encrypt_abc"]);
+
+        let mut use_case = InspectTempUseCase::new(
+            temp_file,
+            Some(original_file),
+            plain_temp_crypto(),
+            original_crypto(b"abc"),
+            MockTerminal,
+        );
+        let result = use_case.execute();
+
+        assert!(result.is_ok());
+        assert_eq!(use_case.status(), TempStatus::Complete);
+    }
+
+    //Проверяем, что совпавшее начало расшифровки считается оборванной записью
+    #[test]
+    fn test_decrypted_partial() {
+        let (temp_file, original_file) = make_files(&[b"ab"], &[b"encrypt_abc"]);
+
+        let mut use_case = InspectTempUseCase::new(
+            temp_file,
+            Some(original_file),
+            plain_temp_crypto(),
+            original_crypto(b"abc"),
+            MockTerminal,
+        );
+        assert!(use_case.execute().is_ok());
+
+        assert_eq!(use_case.status(), TempStatus::Partial);
+    }
+
+    //Проверяем, что расхождение с исходным файлом или его отсутствие не позволяет судить о файле
+    #[test]
+    fn test_decrypted_unknown() {
+        let (temp_file, original_file) = make_files(&[b"xyz"], &[b"encrypt_abc"]);
+        let mut use_case = InspectTempUseCase::new(
+            temp_file,
+            Some(original_file),
+            plain_temp_crypto(),
+            original_crypto(b"abc"),
+            MockTerminal,
+        );
+        assert!(use_case.execute().is_ok());
+        assert_eq!(use_case.status(), TempStatus::Unknown);
+
+        let (temp_file, _) = make_files(&[b"abc"], &[]);
+        let mut use_case = InspectTempUseCase::new(
+            temp_file,
+            None,
+            plain_temp_crypto(),
+            original_crypto(b"abc"),
+            MockTerminal,
+        );
+        assert!(use_case.execute().is_ok());
+        assert_eq!(use_case.status(), TempStatus::Unknown);
+    }
+}
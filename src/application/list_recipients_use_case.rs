@@ -0,0 +1,91 @@
+use super::file_start::read_file_start;
+use crate::domain::models::CommandError;
+use crate::domain::services::{BaseActions, FileService, HeaderService, TerminalService};
+
+/// Выводит получателей зашифрованного файла, читая только заголовок
+pub struct ListRecipientsUseCase<F, H, T> {
+    file_service: F,
+    header_service: H,
+    terminal: T,
+}
+
+impl<F, H, T> ListRecipientsUseCase<F, H, T>
+where
+    F: FileService,
+    H: HeaderService,
+    T: TerminalService,
+{
+    pub fn new(file_service: F, header_service: H, terminal: T) -> Self {
+        Self {
+            file_service,
+            header_service,
+            terminal,
+        }
+    }
+}
+
+impl<F, H, T> BaseActions for ListRecipientsUseCase<F, H, T>
+where
+    F: FileService,
+    H: HeaderService,
+    T: TerminalService,
+{
+    type Error = CommandError<F::Error, H::Error>;
+    fn execute(&mut self) -> Result<(), Self::Error> {
+        self.file_service
+            .init_original()
+            .map_err(CommandError::FileServiceError)?;
+
+        let start = read_file_start(&mut self.file_service, |head| {
+            self.header_service.probe_header(head)
+        })?;
+        let Some(header) = start.header() else {
+            return Err(CommandError::NotEncrypted);
+        };
+
+        let lines = self
+            .header_service
+            .describe(&header)
+            .map_err(CommandError::CryptoService)?;
+        for line in lines {
+            self.terminal.print_msg(line);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mock_service::{MockHeaderService, MockTerminal, MockedFileService};
+    use super::*;
+
+    //Проверяем, что читается только начало файла
+    #[test]
+    fn test_normal() {
+        let mut file_service = MockedFileService::new();
+        file_service.read_chunks = vec![b"header12body".to_vec()];
+        let mut header_service = MockHeaderService::new();
+        header_service.description = vec!["x25519:abc".to_string()];
+
+        let mut use_case = ListRecipientsUseCase::new(file_service, header_service, MockTerminal);
+        let result = use_case.execute();
+
+        let command_called = use_case.file_service.called_method;
+
+        assert!(result.is_ok());
+        assert_eq!(command_called, vec!["init_original", "read_exact_original"]);
+        assert_eq!(use_case.file_service.read_chunks, vec![b"body".to_vec()]);
+    }
+
+    #[test]
+    fn error_short_header() {
+        let mut file_service = MockedFileService::new();
+        file_service.read_chunks = vec![b"CRY".to_vec()];
+
+        let mut use_case =
+            ListRecipientsUseCase::new(file_service, MockHeaderService::new(), MockTerminal);
+        let result = use_case.execute();
+
+        assert!(matches!(result, Err(CommandError::NotEncrypted)));
+    }
+}
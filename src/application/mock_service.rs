@@ -1,4 +1,7 @@
-use crate::domain::services::{ConsoleError, CryptoService, FileService, TerminalService};
+use crate::domain::models::HeaderProbe;
+use crate::domain::services::{
+    ConsoleError, CryptoService, FileService, HeaderService, TerminalService,
+};
 
 pub struct MockError(String);
 
@@ -18,10 +21,12 @@ pub struct MockedFileService<'a> {
     pub called_method: Vec<&'a str>,
     pub ok_init_original: bool,
     pub ok_make_temp: bool,
-    pub ok_rename_temp_as_original: bool,
-    pub ok_delete_original: bool,
+    pub ok_commit: bool,
     pub ok_revert: bool,
     pub ok_write_chunk: bool,
+    /// При `false` чтение после последней части из `read_chunks`
+    /// завершается ошибкой вместо конца файла
+    pub ok_read_chunk: bool,
     pub read_chunks: Vec<Vec<u8>>,
     pub write_chunk: Vec<Vec<u8>>,
 }
@@ -33,10 +38,10 @@ impl<'a> MockedFileService<'a> {
 
             ok_init_original: true,
             ok_make_temp: true,
-            ok_rename_temp_as_original: true,
-            ok_delete_original: true,
+            ok_commit: true,
             ok_revert: true,
             ok_write_chunk: true,
+            ok_read_chunk: true,
 
             read_chunks: vec![],
             write_chunk: vec![],
@@ -65,21 +70,12 @@ impl<'a> FileService for MockedFileService<'a> {
         }
     }
 
-    fn rename_temp_as_original(&mut self) -> Result<&mut Self, Self::Error> {
-        self.called_method.push("rename_temp_as_original");
-        if self.ok_rename_temp_as_original {
+    fn commit(&mut self) -> Result<&mut Self, Self::Error> {
+        self.called_method.push("commit");
+        if self.ok_commit {
             Ok(self)
         } else {
-            Err("MockedFileService.rename_temp_as_original".into())
-        }
-    }
-
-    fn delete_original(&mut self) -> Result<&mut Self, Self::Error> {
-        self.called_method.push("delete_original");
-        if self.ok_delete_original {
-            Ok(self)
-        } else {
-            Err("MockedFileService.delete_original".into())
+            Err("MockedFileService.commit".into())
         }
     }
 
@@ -92,14 +88,38 @@ impl<'a> FileService for MockedFileService<'a> {
         }
     }
 
-    fn read_chunk_original(&mut self) -> Vec<u8> {
+    fn read_chunk_original(&mut self) -> Result<Option<Vec<u8>>, Self::Error> {
         self.called_method.push("read_chunk_original");
 
-        if self.read_chunks.is_empty() {
-            return vec![];
+        if !self.read_chunks.is_empty() {
+            return Ok(Some(self.read_chunks.remove(0)));
         }
+        if self.ok_read_chunk {
+            Ok(None)
+        } else {
+            Err("MockedFileService.read_chunk_original".into())
+        }
+    }
+
+    /// Берёт байты из начала `read_chunks`, разрезая части при необходимости
+    fn read_exact_original(&mut self, len: usize) -> Result<Vec<u8>, Self::Error> {
+        self.called_method.push("read_exact_original");
 
-        self.read_chunks.remove(0)
+        let mut out = Vec::new();
+        while out.len() < len {
+            let Some(chunk) = self.read_chunks.first_mut() else {
+                if self.ok_read_chunk {
+                    break;
+                }
+                return Err("MockedFileService.read_exact_original".into());
+            };
+            let take = (len - out.len()).min(chunk.len());
+            out.extend(chunk.drain(..take));
+            if chunk.is_empty() {
+                self.read_chunks.remove(0);
+            }
+        }
+        Ok(out)
     }
 
     fn write_chunk(&mut self, chunk: Vec<u8>) -> Result<(), Self::Error> {
@@ -114,13 +134,30 @@ impl<'a> FileService for MockedFileService<'a> {
     }
 }
 
+/// Длина заголовка в моках: у `encrypt_abc` заголовок — `encrypt_`
+pub const MOCK_HEADER_LEN: usize = 8;
+
+fn probe_mock_header(head: &[u8]) -> HeaderProbe {
+    match head.len() {
+        len if len < MOCK_HEADER_LEN => HeaderProbe::Incomplete(MOCK_HEADER_LEN - len),
+        _ => HeaderProbe::Complete,
+    }
+}
+
 pub struct MockCryptoService {
     pub ok_decrypt: bool,
     pub ok_encrypt: bool,
-    pub ok_is_encrypt: bool,
+    pub ok_probe_header: bool,
+    pub ok_init_decrypt: bool,
+    pub ok_finish: bool,
     pub is_encrypt: bool,
+    /// Заголовок распознаётся как заголовок устаревшего формата
+    pub is_legacy: bool,
     pub encrypt_chunks: Vec<Vec<u8>>,
+    /// Части, переданные в `encrypt`
+    pub encrypt_input: Vec<Vec<u8>>,
     pub decrypt_chunk: Vec<Vec<u8>>,
+    pub finish_chunk: Vec<u8>,
 }
 
 impl MockCryptoService {
@@ -128,16 +165,41 @@ impl MockCryptoService {
         Self {
             ok_decrypt: true,
             ok_encrypt: true,
-            ok_is_encrypt: true,
+            ok_probe_header: true,
+            ok_init_decrypt: true,
+            ok_finish: true,
             is_encrypt: true,
+            is_legacy: false,
             encrypt_chunks: vec![],
+            encrypt_input: vec![],
             decrypt_chunk: vec![],
+            finish_chunk: vec![],
         }
     }
 }
 
 impl CryptoService for MockCryptoService {
     type Error = MockError;
+    fn probe_header(&self, head: &[u8]) -> Result<HeaderProbe, Self::Error> {
+        if !self.ok_probe_header {
+            return Err(MockError::from("MockCryptoService.probe_header"));
+        }
+        // Как и настоящий заголовок, незашифрованный файл узнаётся по первому байту
+        match self.is_encrypt {
+            true => match probe_mock_header(head) {
+                HeaderProbe::Complete if self.is_legacy => Ok(HeaderProbe::Legacy),
+                probe => Ok(probe),
+            },
+            false if head.is_empty() => Ok(HeaderProbe::Incomplete(1)),
+            false => Ok(HeaderProbe::Plain),
+        }
+    }
+    fn init_decrypt(&mut self, _header: &[u8]) -> Result<(), Self::Error> {
+        if self.ok_init_decrypt {
+            return Ok(());
+        }
+        Err(MockError::from("MockCryptoService.init_decrypt"))
+    }
     fn decrypt(&mut self, _chunk: Vec<u8>) -> Result<Vec<u8>, Self::Error> {
         let result = self.decrypt_chunk[0].clone();
         if self.ok_decrypt {
@@ -145,18 +207,56 @@ impl CryptoService for MockCryptoService {
         }
         Err(MockError::from("MockCryptoService.decrypt"))
     }
-    fn encrypt(&mut self, _chunk: Vec<u8>) -> Result<Vec<u8>, Self::Error> {
+    fn encrypt(&mut self, chunk: Vec<u8>) -> Result<Vec<u8>, Self::Error> {
+        self.encrypt_input.push(chunk);
         let result = self.encrypt_chunks[0].clone();
         if self.ok_encrypt {
             return Ok(result);
         }
         Err(MockError::from("MockCryptoService.encrypt"))
     }
-    fn is_encrypt(&mut self, _chunk: &Vec<u8>) -> Result<bool, Self::Error> {
-        if self.ok_is_encrypt {
-            return Ok(self.is_encrypt);
+    fn finish(&mut self) -> Result<Vec<u8>, Self::Error> {
+        if self.ok_finish {
+            return Ok(self.finish_chunk.clone());
+        }
+        Err(MockError::from("MockCryptoService.finish"))
+    }
+}
+
+pub struct MockHeaderService {
+    pub can_rewrite: bool,
+    pub ok_rewrite: bool,
+    pub new_header: Vec<u8>,
+    pub description: Vec<String>,
+}
+
+impl MockHeaderService {
+    pub fn new() -> Self {
+        Self {
+            can_rewrite: false,
+            ok_rewrite: true,
+            new_header: vec![],
+            description: vec![],
+        }
+    }
+}
+
+impl HeaderService for MockHeaderService {
+    type Error = MockError;
+    fn probe_header(&self, head: &[u8]) -> Result<HeaderProbe, Self::Error> {
+        Ok(probe_mock_header(head))
+    }
+    fn can_rewrite(&mut self, _header: &[u8]) -> Result<bool, Self::Error> {
+        Ok(self.can_rewrite)
+    }
+    fn rewrite(&mut self, _header: &[u8]) -> Result<Vec<u8>, Self::Error> {
+        if self.ok_rewrite {
+            return Ok(self.new_header.clone());
         }
-        Err(MockError::from("MockCryptoService.is_encrypt"))
+        Err(MockError::from("MockHeaderService.rewrite"))
+    }
+    fn describe(&mut self, _header: &[u8]) -> Result<Vec<String>, Self::Error> {
+        Ok(self.description.clone())
     }
 }
 
@@ -177,4 +277,7 @@ impl TerminalService for MockTerminal {
         let text = String::from_utf8_lossy(&value);
         print!("{text}");
     }
+    fn ask(&self, _question: String) -> Option<String> {
+        None
+    }
 }
@@ -1,4 +1,12 @@
 pub mod decrypt_use_case;
+pub mod file_start;
+pub mod inspect_temp_use_case;
+pub mod list_recipients_use_case;
+#[cfg(test)]
 mod mock_service;
 pub mod prepare_use_case;
+pub mod read_format;
 pub mod read_use_case;
+pub mod rekey_use_case;
+pub mod revert;
+pub mod rewrite_header_use_case;
@@ -1,8 +1,14 @@
-use crate::domain::models::CommandError;
+use crate::domain::models::{AlreadyProcessed, CancelToken, CommandError};
 use crate::domain::services::{BaseActions, CryptoService, FileService, TerminalService};
 
+use super::file_start::{FileStart, read_file_start};
+use super::revert::revert_on_err;
+
 pub struct PrepareUseCase<F, C, T> {
     counter: usize,
+    on_already: AlreadyProcessed,
+    skipped: bool,
+    cancel: CancelToken,
     file_service: F,
     crypto_service: C,
     terminal: T,
@@ -17,12 +23,35 @@ where
     pub fn new(file_service: F, crypto_service: C, terminal: T) -> Self {
         Self {
             counter: 0,
+            on_already: AlreadyProcessed::Force,
+            skipped: false,
+            cancel: CancelToken::default(),
             file_service,
             crypto_service,
             terminal,
         }
     }
 
+    /// Что делать, если файл уже зашифрован. Для проверки заголовок
+    /// читается до создания временного файла. По умолчанию
+    /// (`Force`) файл обрабатывается без проверки.
+    pub fn on_already(mut self, policy: AlreadyProcessed) -> Self {
+        self.on_already = policy;
+        self
+    }
+
+    /// Токен отмены: после сигнала временный файл удаляется,
+    /// а исходный файл остаётся нетронутым
+    pub fn with_cancel(mut self, cancel: CancelToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Был ли файл пропущен по политике `AlreadyProcessed::Skip`
+    pub fn is_skipped(&self) -> bool {
+        self.skipped
+    }
+
     fn show_counter(&mut self) {
         self.counter += 1;
         self.terminal
@@ -40,38 +69,100 @@ where
     fn execute(&mut self) -> Result<(), Self::Error> {
         self.file_service
             .init_original()
-            .and_then(FileService::make_temp)
+            .map_err(CommandError::FileServiceError)?;
+
+        let mut head = None;
+        if self.on_already != AlreadyProcessed::Force {
+            let start = read_file_start(&mut self.file_service, |head| {
+                self.crypto_service.probe_header(head)
+            })?;
+            match start {
+                FileStart::Header(_) if self.on_already == AlreadyProcessed::Error => {
+                    return Err(CommandError::AlreadyEncrypted);
+                }
+                FileStart::Header(_) => {
+                    self.skipped = true;
+                    self.terminal
+                        .print_msg("Файл уже зашифрован, пропущен".to_string());
+                    return Ok(());
+                }
+                FileStart::Legacy(_) if self.on_already == AlreadyProcessed::Error => {
+                    return Err(CommandError::AmbiguousLegacy);
+                }
+                FileStart::Legacy(_) => {
+                    self.skipped = true;
+                    self.terminal.print_msg(
+                        "Файл начинается с CRYPT1: это файл устаревшего формата или данные \
+                         с таким началом. Пропущен, проверьте его командой read, для шифрования \
+                         укажите --on-already force"
+                            .to_string(),
+                    );
+                    return Ok(());
+                }
+                FileStart::Plain(start) => head = (!start.is_empty()).then_some(start),
+            }
+        }
+
+        self.file_service
+            .make_temp()
             .map_err(CommandError::FileServiceError)?;
 
         loop {
-            let chunk = self.file_service.read_chunk_original();
-            if chunk.is_empty() {
+            revert_on_err(&mut self.file_service, self.cancel.check())?;
+
+            let result = self
+                .file_service
+                .read_chunk_original()
+                .map_err(CommandError::FileServiceError);
+            let chunk = revert_on_err(&mut self.file_service, result)?;
+            // Начало файла, прочитанное при проверке заголовка, шифруется
+            // вместе с первой частью, а не отдельной частью
+            let chunk = match (head.take(), chunk) {
+                (Some(mut head), Some(chunk)) => {
+                    head.extend(chunk);
+                    Some(head)
+                }
+                (head, chunk) => chunk.or(head),
+            };
+            let Some(chunk) = chunk else {
                 break;
-            }
+            };
 
             self.show_counter();
 
-            let encrypted = self
-                .crypto_service
-                .encrypt(chunk)
-                .map_err(CommandError::CryptoService)
-                .map_err(|e| match self.file_service.revert() {
-                    Ok(_) => e,
-                    Err(err) => CommandError::FileServiceError(err),
-                })?;
+            let encrypted = revert_on_err(
+                &mut self.file_service,
+                self.crypto_service
+                    .encrypt(chunk)
+                    .map_err(CommandError::CryptoService),
+            )?;
 
-            self.file_service
+            let result = self
+                .file_service
                 .write_chunk(encrypted)
-                .map_err(CommandError::FileServiceError)
-                .map_err(|e| match self.file_service.revert() {
-                    Ok(_) => e,
-                    Err(err) => CommandError::FileServiceError(err),
-                })?;
+                .map_err(CommandError::FileServiceError);
+            revert_on_err(&mut self.file_service, result)?;
         }
 
+        let last_chunk = revert_on_err(
+            &mut self.file_service,
+            self.crypto_service
+                .finish()
+                .map_err(CommandError::CryptoService),
+        )?;
+
+        if !last_chunk.is_empty() {
+            let result = self
+                .file_service
+                .write_chunk(last_chunk)
+                .map_err(CommandError::FileServiceError);
+            revert_on_err(&mut self.file_service, result)?;
+        }
+
+        revert_on_err(&mut self.file_service, self.cancel.check())?;
+
         self.file_service
-            .delete_original()
-            .and_then(FileService::rename_temp_as_original)
+            .commit()
             .map_err(CommandError::FileServiceError)?;
         Ok(())
     }
@@ -106,11 +197,30 @@ mod tests {
         assert_eq!(command_called[4], "read_chunk_original");
         assert_eq!(command_called[5], "write_chunk");
         assert_eq!(command_called[6], "read_chunk_original");
-        assert_eq!(command_called[7], "delete_original");
-        assert_eq!(command_called[8], "rename_temp_as_original");
+        assert_eq!(command_called[7], "commit");
         assert_eq!(write_chunks[0], encrypt_chunks[0]);
     }
 
+    //Проверяем, что пустой файл не пропускается и получает заголовок из finish
+    #[test]
+    fn test_empty_file() {
+        let mut crypto_service = MockCryptoService::new();
+        crypto_service.finish_chunk = b"encrypt_".to_vec();
+
+        let mut use_case =
+            PrepareUseCase::new(MockedFileService::new(), crypto_service, MockTerminal)
+                .on_already(AlreadyProcessed::Skip);
+        let result = use_case.execute();
+
+        assert!(result.is_ok());
+        assert!(!use_case.is_skipped());
+        assert_eq!(
+            use_case.file_service.write_chunk,
+            vec![b"encrypt_".to_vec()]
+        );
+        assert_eq!(use_case.file_service.called_method.last(), Some(&"commit"));
+    }
+
     //Проверяем, что в случае ошибки шифрования вызывается revert
     #[test]
     fn error_encrypt() {
@@ -160,4 +270,156 @@ mod tests {
         assert_eq!(command_called[3], "write_chunk");
         assert_eq!(command_called[4], "revert");
     }
+
+    //Проверяем, что уже зашифрованный файл не трогается
+    #[test]
+    fn test_skip_encrypted() {
+        let mut file_service = MockedFileService::new();
+        file_service.read_chunks = vec![b"encrypt_abc".to_vec()];
+
+        let mut use_case =
+            PrepareUseCase::new(file_service, MockCryptoService::new(), MockTerminal)
+                .on_already(AlreadyProcessed::Skip);
+        let result = use_case.execute();
+
+        assert!(result.is_ok());
+        assert!(use_case.is_skipped());
+        assert_eq!(
+            use_case.file_service.called_method,
+            vec!["init_original", "read_exact_original"]
+        );
+    }
+
+    //Проверяем, что прочитанное для проверки начало файла шифруется вместе
+    //с первой частью и не добавляет лишнюю часть
+    #[test]
+    fn test_skip_processed_plain_file() {
+        let mut file_service = MockedFileService::new();
+        file_service.read_chunks = vec![b"abc".to_vec(), b"def".to_vec()];
+        let mut crypto_service = MockCryptoService::new();
+        crypto_service.is_encrypt = false;
+        crypto_service.encrypt_chunks = vec![b"encrypt_abc".to_vec()];
+
+        let mut use_case = PrepareUseCase::new(file_service, crypto_service, MockTerminal)
+            .on_already(AlreadyProcessed::Skip);
+        let result = use_case.execute();
+
+        assert!(result.is_ok());
+        assert!(!use_case.is_skipped());
+        assert_eq!(use_case.counter, 2);
+        assert_eq!(
+            use_case.crypto_service.encrypt_input,
+            vec![b"abc".to_vec(), b"def".to_vec()]
+        );
+        assert_eq!(
+            use_case.file_service.called_method,
+            vec![
+                "init_original",
+                "read_exact_original",
+                "make_temp",
+                "read_chunk_original",
+                "write_chunk",
+                "read_chunk_original",
+                "write_chunk",
+                "read_chunk_original",
+                "commit"
+            ]
+        );
+    }
+
+    //Проверяем, что после отмены временный файл удаляется, а исходный не заменяется
+    #[test]
+    fn test_cancelled() {
+        let mut file_service = MockedFileService::new();
+        file_service.read_chunks = vec![b"abc".to_vec()];
+        let cancel = CancelToken::default();
+        cancel.cancel();
+
+        let mut use_case =
+            PrepareUseCase::new(file_service, MockCryptoService::new(), MockTerminal)
+                .with_cancel(cancel);
+        let result = use_case.execute();
+
+        assert!(matches!(result, Err(CommandError::Cancelled)));
+        assert_eq!(
+            use_case.file_service.called_method,
+            vec!["init_original", "make_temp", "revert"]
+        );
+    }
+
+    //Проверяем, что ошибка чтения посреди файла не принимается за его конец:
+    //временный файл удаляется, а исходный не заменяется
+    #[test]
+    fn test_read_error() {
+        let mut file_service = MockedFileService::new();
+        file_service.read_chunks = vec![b"abc".to_vec()];
+        file_service.ok_read_chunk = false;
+        let mut crypto_service = MockCryptoService::new();
+        crypto_service.encrypt_chunks = vec![b"encrypt_abc".to_vec()];
+
+        let mut use_case = PrepareUseCase::new(file_service, crypto_service, MockTerminal);
+        let result = use_case.execute();
+
+        assert!(matches!(result, Err(CommandError::FileServiceError(_))));
+        assert_eq!(
+            use_case.file_service.called_method,
+            vec![
+                "init_original",
+                "make_temp",
+                "read_chunk_original",
+                "write_chunk",
+                "read_chunk_original",
+                "revert"
+            ]
+        );
+    }
+
+    //Проверяем, что с политикой Error зашифрованный файл не шифруется повторно,
+    //а временный файл не создаётся
+    #[test]
+    fn test_already_encrypted_error() {
+        let mut file_service = MockedFileService::new();
+        file_service.read_chunks = vec![b"encrypt_abc".to_vec()];
+
+        let mut use_case =
+            PrepareUseCase::new(file_service, MockCryptoService::new(), MockTerminal)
+                .on_already(AlreadyProcessed::Error);
+        let result = use_case.execute();
+
+        assert!(matches!(result, Err(CommandError::AlreadyEncrypted)));
+        assert!(!use_case.is_skipped());
+        assert_eq!(
+            use_case.file_service.called_method,
+            vec!["init_original", "read_exact_original"]
+        );
+    }
+
+    //Проверяем, что файл с заголовком устаревшего формата не пропускается молча
+    //как зашифрованный: с политикой Error это отдельная ошибка
+    #[test]
+    fn test_legacy_is_ambiguous() {
+        for (policy, skipped) in [
+            (AlreadyProcessed::Skip, true),
+            (AlreadyProcessed::Error, false),
+        ] {
+            let mut file_service = MockedFileService::new();
+            file_service.read_chunks = vec![b"encrypt_abc".to_vec()];
+            let mut crypto_service = MockCryptoService::new();
+            crypto_service.is_legacy = true;
+
+            let mut use_case =
+                PrepareUseCase::new(file_service, crypto_service, MockTerminal).on_already(policy);
+            let result = use_case.execute();
+
+            assert_eq!(
+                matches!(result, Err(CommandError::AmbiguousLegacy)),
+                !skipped
+            );
+            assert_eq!(use_case.is_skipped(), skipped);
+            assert_eq!(
+                use_case.file_service.called_method,
+                vec!["init_original", "read_exact_original"]
+            );
+        }
+    }
 }
@@ -0,0 +1,171 @@
+use std::fmt::Write;
+
+use crate::domain::models::ReadFormat;
+
+/// Байт в строке шестнадцатеричного дампа
+const HEX_LINE: usize = 16;
+
+/// Преобразует расшифрованные части файла в вид для вывода.
+///
+/// Границы частей не совпадают с границами символов UTF-8 и строк дампа,
+/// поэтому незавершённый хвост части откладывается до следующей,
+/// а остаток выводится в `finish`.
+pub struct ChunkFormatter {
+    format: ReadFormat,
+    pending: Vec<u8>,
+    offset: usize,
+}
+
+impl ChunkFormatter {
+    pub fn new(format: ReadFormat) -> Self {
+        Self {
+            format,
+            pending: Vec::new(),
+            offset: 0,
+        }
+    }
+
+    pub fn format(&mut self, chunk: Vec<u8>) -> Vec<u8> {
+        match self.format {
+            ReadFormat::Raw => chunk,
+            ReadFormat::Text => {
+                self.pending.extend(chunk);
+                self.decode_text()
+            }
+            ReadFormat::Hex => {
+                self.pending.extend(chunk);
+                let full = self.pending.len() - self.pending.len() % HEX_LINE;
+                let lines: Vec<u8> = self.pending.drain(..full).collect();
+                self.dump(&lines)
+            }
+        }
+    }
+
+    /// Выводит отложенный остаток: обрезанный в конце файла символ
+    /// заменяется на `�`, неполная строка дампа выводится как есть
+    pub fn finish(&mut self) -> Vec<u8> {
+        let rest = std::mem::take(&mut self.pending);
+        match self.format {
+            ReadFormat::Raw => rest,
+            ReadFormat::Text => String::from_utf8_lossy(&rest).into_owned().into_bytes(),
+            ReadFormat::Hex => self.dump(&rest),
+        }
+    }
+
+    /// Декодирует накопленные байты до последнего полного символа.
+    /// Некорректные последовательности заменяются на `�`, незавершённая
+    /// в конце остаётся в `pending`.
+    fn decode_text(&mut self) -> Vec<u8> {
+        let mut text = String::new();
+        let mut rest = self.pending.as_slice();
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    rest = &[];
+                    break;
+                }
+                Err(e) => {
+                    let (valid, invalid) = rest.split_at(e.valid_up_to());
+                    text.push_str(std::str::from_utf8(valid).unwrap_or_default());
+                    match e.error_len() {
+                        Some(len) => {
+                            text.push(char::REPLACEMENT_CHARACTER);
+                            rest = &invalid[len..];
+                        }
+                        None => {
+                            rest = invalid;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        self.pending = rest.to_vec();
+        text.into_bytes()
+    }
+
+    /// Строки вида `00000010  48 65 6c 6c 6f 20 77 6f  72 6c 64 0a  |Hello world.|`
+    fn dump(&mut self, bytes: &[u8]) -> Vec<u8> {
+        let mut out = String::new();
+        for line in bytes.chunks(HEX_LINE) {
+            let _ = write!(out, "{:08x} ", self.offset);
+            for i in 0..HEX_LINE {
+                if i % 8 == 0 {
+                    out.push(' ');
+                }
+                match line.get(i) {
+                    Some(byte) => {
+                        let _ = write!(out, "{byte:02x} ");
+                    }
+                    None => out.push_str("   "),
+                }
+            }
+            let ascii: String = line
+                .iter()
+                .map(|&b| match b.is_ascii_graphic() || b == b' ' {
+                    true => b as char,
+                    false => '.',
+                })
+                .collect();
+            let _ = writeln!(out, " |{ascii}|");
+            self.offset += line.len();
+        }
+        out.into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format_all(format: ReadFormat, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut formatter = ChunkFormatter::new(format);
+        let mut out = Vec::new();
+        for chunk in chunks {
+            out.extend(formatter.format(chunk.to_vec()));
+        }
+        out.extend(formatter.finish());
+        out
+    }
+
+    //Проверяем, что байты без формата выводятся без изменений
+    #[test]
+    fn test_raw() {
+        let out = format_all(ReadFormat::Raw, &[&[0xff, 0x00], &[0x89, b'P']]);
+        assert_eq!(out, vec![0xff, 0x00, 0x89, b'P']);
+    }
+
+    //Проверяем, что символ, разрезанный границей частей, декодируется целиком
+    #[test]
+    fn test_text_split_char() {
+        let text = "привет".as_bytes();
+        let chunks: Vec<&[u8]> = text.chunks(3).collect();
+
+        let out = format_all(ReadFormat::Text, &chunks);
+
+        assert_eq!(String::from_utf8(out).unwrap(), "привет");
+    }
+
+    //Проверяем, что некорректные байты и обрезанный в конце символ заменяются на �
+    #[test]
+    fn test_text_invalid() {
+        let out = format_all(ReadFormat::Text, &[b"a\xffb", b"\xd0"]);
+        assert_eq!(String::from_utf8(out).unwrap(), "a\u{fffd}b\u{fffd}");
+    }
+
+    //Проверяем, что смещения и строки дампа не зависят от границ частей
+    #[test]
+    fn test_hex() {
+        let data = b"Hello, world! 0123456789";
+        let whole = format_all(ReadFormat::Hex, &[data]);
+        let split = format_all(ReadFormat::Hex, &[&data[..5], &data[5..19], &data[19..]]);
+
+        assert_eq!(whole, split);
+        assert_eq!(
+            String::from_utf8(whole).unwrap(),
+            "00000000  48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 20 30 31  |Hello, world! 01|\n\
+             00000010  32 33 34 35 36 37 38 39                           |23456789|\n"
+        );
+    }
+}
@@ -1,9 +1,13 @@
-use crate::domain::models::CommandError;
+use crate::domain::models::{CancelToken, CommandError, ReadFormat};
 use crate::domain::services::{BaseActions, CryptoService, FileService, TerminalService};
 
+use super::file_start::{FileStart, read_file_start};
+use super::read_format::ChunkFormatter;
+
 pub struct ReadUseCase<F, C, T> {
-    is_first_chunk: bool,
     is_encrypted: bool,
+    cancel: CancelToken,
+    formatter: ChunkFormatter,
     file_service: F,
     crypto_service: C,
     terminal: T,
@@ -17,13 +21,33 @@ where
 {
     pub fn new(file_service: F, crypto_service: C, terminal: T) -> Self {
         Self {
-            is_first_chunk: true,
             is_encrypted: false,
+            cancel: CancelToken::default(),
+            formatter: ChunkFormatter::new(ReadFormat::Raw),
             file_service,
             crypto_service,
             terminal,
         }
     }
+
+    /// Токен отмены: после сигнала вывод прекращается
+    pub fn with_cancel(mut self, cancel: CancelToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Вид вывода, по умолчанию байты файла без изменений
+    pub fn with_format(mut self, format: ReadFormat) -> Self {
+        self.formatter = ChunkFormatter::new(format);
+        self
+    }
+
+    fn print(&mut self, chunk: Vec<u8>) {
+        let output = self.formatter.format(chunk);
+        if !output.is_empty() {
+            self.terminal.print_chunk(output);
+        }
+    }
 }
 
 impl<F, C, T> BaseActions for ReadUseCase<F, C, T>
@@ -37,32 +61,51 @@ where
         self.file_service
             .init_original()
             .map_err(CommandError::FileServiceError)?;
-        loop {
-            let chunk = self.file_service.read_chunk_original();
-            if chunk.is_empty() {
-                break;
-            }
-
-            if self.is_first_chunk {
-                self.is_first_chunk = false;
-                self.is_encrypted = self
-                    .crypto_service
-                    .is_encrypt(&chunk)
+        let start = read_file_start(&mut self.file_service, |head| {
+            self.crypto_service.probe_header(head)
+        })?;
+        match start {
+            FileStart::Header(header) | FileStart::Legacy(header) => {
+                self.is_encrypted = true;
+                self.crypto_service
+                    .init_decrypt(&header)
                     .map_err(CommandError::CryptoService)?;
             }
+            FileStart::Plain(head) => self.print(head),
+        }
+
+        loop {
+            self.cancel.check()?;
+
+            let chunk = self
+                .file_service
+                .read_chunk_original()
+                .map_err(CommandError::FileServiceError)?;
+            let Some(chunk) = chunk else {
+                break;
+            };
 
             let clear_chunk = if self.is_encrypted {
                 self.crypto_service
                     .decrypt(chunk)
-                    .map_err(CommandError::CryptoService)
-                    .map_err(|e| match self.file_service.revert() {
-                        Ok(_) => e,
-                        Err(err) => CommandError::FileServiceError(err),
-                    })?
+                    .map_err(CommandError::CryptoService)?
             } else {
                 chunk
             };
-            self.terminal.print_chunk(clear_chunk);
+            self.print(clear_chunk);
+        }
+
+        if self.is_encrypted {
+            let last_chunk = self
+                .crypto_service
+                .finish()
+                .map_err(CommandError::CryptoService)?;
+            self.print(last_chunk);
+        }
+
+        let rest = self.formatter.finish();
+        if !rest.is_empty() {
+            self.terminal.print_chunk(rest);
         }
         Ok(())
     }
@@ -0,0 +1,456 @@
+use super::file_start::{FileStart, read_file_start};
+use super::revert::revert_on_err;
+use crate::domain::models::{CancelToken, CommandError};
+use crate::domain::services::{
+    BaseActions, CryptoService, FileService, HeaderService, TerminalService,
+};
+
+/// Перешифровывает файл новым ключом. Если ключ данных файла завёрнут
+/// мастер-ключом, заменяется только заголовок: ключ данных и префикс nonce
+/// остаются прежними. Иначе, а также с `full`, расшифрованные данные сразу
+/// шифруются заново со свежим ключом данных и nonce и не попадают на диск
+/// в открытом виде.
+pub struct RekeyUseCase<F, C, H, T> {
+    counter: usize,
+    rewrap: bool,
+    full: bool,
+    skipped: bool,
+    allow_legacy: bool,
+    cancel: CancelToken,
+    file_service: F,
+    decrypt_service: C,
+    encrypt_service: C,
+    header_service: H,
+    terminal: T,
+}
+
+impl<F, C, H, T> RekeyUseCase<F, C, H, T>
+where
+    F: FileService,
+    C: CryptoService,
+    H: HeaderService<Error = C::Error>,
+    T: TerminalService,
+{
+    pub fn new(
+        file_service: F,
+        decrypt_service: C,
+        encrypt_service: C,
+        header_service: H,
+        terminal: T,
+    ) -> Self {
+        Self {
+            counter: 0,
+            rewrap: false,
+            full: false,
+            skipped: false,
+            allow_legacy: false,
+            cancel: CancelToken::default(),
+            file_service,
+            decrypt_service,
+            encrypt_service,
+            header_service,
+            terminal,
+        }
+    }
+
+    /// Перешифровывать ли файл устаревшего формата. В его заголовке нет проверки
+    /// ключа, поэтому с неверным ключом результат — мусор, который без
+    /// разрешения не должен заменять исходный файл.
+    pub fn allow_legacy(mut self, allow: bool) -> Self {
+        self.allow_legacy = allow;
+        self
+    }
+
+    /// Перешифровывать файл целиком, даже если можно заменить только заголовок
+    pub fn full(mut self, full: bool) -> Self {
+        self.full = full;
+        self
+    }
+
+    /// Токен отмены: после сигнала временный файл удаляется,
+    /// а исходный файл остаётся нетронутым
+    pub fn with_cancel(mut self, cancel: CancelToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Был ли файл пропущен, потому что он не зашифрован
+    pub fn is_skipped(&self) -> bool {
+        self.skipped
+    }
+
+    fn show_counter(&mut self) {
+        self.counter += 1;
+        self.terminal
+            .print_message_in_line(format!("Перешифровывается часть {}", self.counter));
+    }
+
+    fn reencrypt(&mut self, chunk: Vec<u8>) -> Result<Vec<u8>, CommandError<F::Error, C::Error>> {
+        if self.rewrap {
+            return Ok(chunk);
+        }
+
+        let clear_chunk = self
+            .decrypt_service
+            .decrypt(chunk)
+            .map_err(CommandError::CryptoService)?;
+
+        self.encrypt_service
+            .encrypt(clear_chunk)
+            .map_err(CommandError::CryptoService)
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>, CommandError<F::Error, C::Error>> {
+        if self.rewrap {
+            return Ok(Vec::new());
+        }
+
+        let clear_chunk = self
+            .decrypt_service
+            .finish()
+            .map_err(CommandError::CryptoService)?;
+
+        let mut out = self
+            .encrypt_service
+            .encrypt(clear_chunk)
+            .map_err(CommandError::CryptoService)?;
+        out.extend(
+            self.encrypt_service
+                .finish()
+                .map_err(CommandError::CryptoService)?,
+        );
+        Ok(out)
+    }
+}
+
+impl<F, C, H, T> BaseActions for RekeyUseCase<F, C, H, T>
+where
+    F: FileService,
+    C: CryptoService,
+    H: HeaderService<Error = C::Error>,
+    T: TerminalService,
+{
+    type Error = CommandError<F::Error, C::Error>;
+    fn execute(&mut self) -> Result<(), Self::Error> {
+        self.file_service
+            .init_original()
+            .map_err(CommandError::FileServiceError)?;
+
+        let start = read_file_start(&mut self.file_service, |head| {
+            self.decrypt_service.probe_header(head)
+        })?;
+        let header = match start {
+            FileStart::Header(header) => header,
+            FileStart::Legacy(_) if !self.allow_legacy => {
+                return Err(CommandError::UncheckedLegacy);
+            }
+            FileStart::Legacy(header) => header,
+            FileStart::Plain(_) => {
+                self.skipped = true;
+                self.terminal
+                    .print_msg("Файл не зашифрован, пропущен".to_string());
+                return Ok(());
+            }
+        };
+
+        self.rewrap = !self.full
+            && self
+                .header_service
+                .can_rewrite(&header)
+                .map_err(CommandError::CryptoService)?;
+
+        let new_header = if self.rewrap {
+            self.header_service
+                .rewrite(&header)
+                .map_err(CommandError::CryptoService)?
+        } else {
+            self.decrypt_service
+                .init_decrypt(&header)
+                .map_err(CommandError::CryptoService)?;
+            Vec::new()
+        };
+
+        self.file_service
+            .make_temp()
+            .map_err(CommandError::FileServiceError)?;
+
+        if !new_header.is_empty() {
+            let result = self
+                .file_service
+                .write_chunk(new_header)
+                .map_err(CommandError::FileServiceError);
+            revert_on_err(&mut self.file_service, result)?;
+        }
+
+        loop {
+            revert_on_err(&mut self.file_service, self.cancel.check())?;
+
+            let result = self
+                .file_service
+                .read_chunk_original()
+                .map_err(CommandError::FileServiceError);
+            let chunk = revert_on_err(&mut self.file_service, result)?;
+            let Some(chunk) = chunk else {
+                break;
+            };
+
+            self.show_counter();
+
+            let result = self.reencrypt(chunk);
+            let encrypted = revert_on_err(&mut self.file_service, result)?;
+
+            let result = self
+                .file_service
+                .write_chunk(encrypted)
+                .map_err(CommandError::FileServiceError);
+            revert_on_err(&mut self.file_service, result)?;
+        }
+
+        let result = self.finish();
+        let last_chunk = revert_on_err(&mut self.file_service, result)?;
+
+        if !last_chunk.is_empty() {
+            let result = self
+                .file_service
+                .write_chunk(last_chunk)
+                .map_err(CommandError::FileServiceError);
+            revert_on_err(&mut self.file_service, result)?;
+        }
+
+        revert_on_err(&mut self.file_service, self.cancel.check())?;
+
+        self.file_service
+            .commit()
+            .map_err(CommandError::FileServiceError)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mock_service::{
+        MockCryptoService, MockHeaderService, MockTerminal, MockedFileService,
+    };
+    use super::*;
+
+    fn make_crypto_services() -> (MockCryptoService, MockCryptoService) {
+        let mut decrypt_service = MockCryptoService::new();
+        decrypt_service.decrypt_chunk = vec![b"abc".to_vec()];
+
+        let mut encrypt_service = MockCryptoService::new();
+        encrypt_service.encrypt_chunks = vec![b"new_encrypt_abc".to_vec()];
+
+        (decrypt_service, encrypt_service)
+    }
+
+    #[test]
+    fn test_normal() {
+        let mut file_service = MockedFileService::new();
+        file_service.read_chunks = vec![b"old_encrypt_abc".to_vec()];
+        let (decrypt_service, encrypt_service) = make_crypto_services();
+
+        let mut use_case = RekeyUseCase::new(
+            file_service,
+            decrypt_service,
+            encrypt_service,
+            MockHeaderService::new(),
+            MockTerminal,
+        );
+        let result = use_case.execute();
+
+        let command_called = use_case.file_service.called_method;
+        let write_chunks = use_case.file_service.write_chunk;
+
+        assert!(result.is_ok());
+        assert_eq!(command_called[0], "init_original");
+        assert_eq!(command_called[1], "read_exact_original");
+        assert_eq!(command_called[2], "make_temp");
+        assert_eq!(command_called[3], "read_chunk_original");
+        assert_eq!(command_called[4], "write_chunk");
+        assert_eq!(command_called[5], "read_chunk_original");
+        assert_eq!(command_called[6], "write_chunk");
+        assert_eq!(command_called[7], "commit");
+        assert_eq!(write_chunks[0], b"new_encrypt_abc".to_vec());
+    }
+
+    //Проверяем, что незашифрованный файл не трогается
+    #[test]
+    fn test_plain_file_skipped() {
+        let mut file_service = MockedFileService::new();
+        file_service.read_chunks = vec![b"abc".to_vec()];
+        let (mut decrypt_service, encrypt_service) = make_crypto_services();
+        decrypt_service.is_encrypt = false;
+
+        let mut use_case = RekeyUseCase::new(
+            file_service,
+            decrypt_service,
+            encrypt_service,
+            MockHeaderService::new(),
+            MockTerminal,
+        );
+        let result = use_case.execute();
+
+        let command_called = use_case.file_service.called_method;
+
+        assert!(result.is_ok());
+        assert_eq!(command_called, vec!["init_original", "read_exact_original"]);
+    }
+
+    //Проверяем, что в случае ошибки расшифровки вызывается revert
+    #[test]
+    fn error_decrypt() {
+        let mut file_service = MockedFileService::new();
+        file_service.read_chunks = vec![b"old_encrypt_abc".to_vec()];
+        let (mut decrypt_service, encrypt_service) = make_crypto_services();
+        decrypt_service.ok_decrypt = false;
+
+        let mut use_case = RekeyUseCase::new(
+            file_service,
+            decrypt_service,
+            encrypt_service,
+            MockHeaderService::new(),
+            MockTerminal,
+        );
+        let result = use_case.execute();
+
+        let command_called = use_case.file_service.called_method;
+
+        assert!(result.is_err());
+        assert_eq!(command_called[2], "make_temp");
+        assert_eq!(command_called.last(), Some(&"revert"));
+        assert!(!command_called.contains(&"commit"));
+    }
+
+    //Проверяем, что при переупаковке ключа данные не перешифровываются
+    #[test]
+    fn test_rewrap_header_only() {
+        let mut file_service = MockedFileService::new();
+        file_service.read_chunks = vec![b"old_headabc".to_vec()];
+        let (mut decrypt_service, encrypt_service) = make_crypto_services();
+        decrypt_service.ok_decrypt = false;
+        let mut header_service = MockHeaderService::new();
+        header_service.can_rewrite = true;
+        header_service.new_header = b"new_head".to_vec();
+
+        let mut use_case = RekeyUseCase::new(
+            file_service,
+            decrypt_service,
+            encrypt_service,
+            header_service,
+            MockTerminal,
+        );
+        let result = use_case.execute();
+
+        let command_called = use_case.file_service.called_method;
+        let write_chunks = use_case.file_service.write_chunk;
+
+        assert!(result.is_ok());
+        assert_eq!(write_chunks, vec![b"new_head".to_vec(), b"abc".to_vec()]);
+        assert_eq!(command_called.last(), Some(&"commit"));
+    }
+
+    //Проверяем, что с full файл перешифровывается целиком, хотя заголовок
+    //можно было бы заменить
+    #[test]
+    fn test_full_reencrypts() {
+        let mut file_service = MockedFileService::new();
+        file_service.read_chunks = vec![b"old_encrypt_abc".to_vec()];
+        let (decrypt_service, encrypt_service) = make_crypto_services();
+        let mut header_service = MockHeaderService::new();
+        header_service.can_rewrite = true;
+        header_service.ok_rewrite = false;
+
+        let mut use_case = RekeyUseCase::new(
+            file_service,
+            decrypt_service,
+            encrypt_service,
+            header_service,
+            MockTerminal,
+        )
+        .full(true);
+        let result = use_case.execute();
+
+        assert!(result.is_ok());
+        assert_eq!(
+            use_case.file_service.write_chunk[0],
+            b"new_encrypt_abc".to_vec()
+        );
+    }
+
+    //Проверяем, что при ошибке замены заголовка временный файл не создаётся
+    #[test]
+    fn error_rewrite() {
+        let mut file_service = MockedFileService::new();
+        file_service.read_chunks = vec![b"old_headabc".to_vec()];
+        let (decrypt_service, encrypt_service) = make_crypto_services();
+        let mut header_service = MockHeaderService::new();
+        header_service.can_rewrite = true;
+        header_service.ok_rewrite = false;
+
+        let mut use_case = RekeyUseCase::new(
+            file_service,
+            decrypt_service,
+            encrypt_service,
+            header_service,
+            MockTerminal,
+        );
+        let result = use_case.execute();
+
+        let command_called = use_case.file_service.called_method;
+
+        assert!(result.is_err());
+        assert!(!command_called.contains(&"make_temp"));
+        assert!(!command_called.contains(&"commit"));
+    }
+
+    //Проверяем, что после отмены временный файл удаляется, а исходный не заменяется
+    #[test]
+    fn test_cancelled() {
+        let mut file_service = MockedFileService::new();
+        file_service.read_chunks = vec![b"old_encrypt_abc".to_vec()];
+        let (decrypt_service, encrypt_service) = make_crypto_services();
+        let cancel = CancelToken::default();
+        cancel.cancel();
+
+        let mut use_case = RekeyUseCase::new(
+            file_service,
+            decrypt_service,
+            encrypt_service,
+            MockHeaderService::new(),
+            MockTerminal,
+        )
+        .with_cancel(cancel);
+        let result = use_case.execute();
+
+        let command_called = use_case.file_service.called_method;
+
+        assert!(matches!(result, Err(CommandError::Cancelled)));
+        assert_eq!(command_called[2], "make_temp");
+        assert_eq!(command_called[3], "revert");
+        assert!(!command_called.contains(&"commit"));
+    }
+
+    //Проверяем, что ошибка чтения посреди файла не принимается за его конец
+    #[test]
+    fn test_read_error() {
+        let mut file_service = MockedFileService::new();
+        file_service.read_chunks = vec![b"old_encrypt_abc".to_vec()];
+        file_service.ok_read_chunk = false;
+        let (decrypt_service, encrypt_service) = make_crypto_services();
+
+        let mut use_case = RekeyUseCase::new(
+            file_service,
+            decrypt_service,
+            encrypt_service,
+            MockHeaderService::new(),
+            MockTerminal,
+        );
+        let result = use_case.execute();
+
+        let command_called = use_case.file_service.called_method;
+
+        assert!(matches!(result, Err(CommandError::FileServiceError(_))));
+        assert_eq!(command_called.last(), Some(&"revert"));
+        assert!(!command_called.contains(&"commit"));
+    }
+}
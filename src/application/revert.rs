@@ -0,0 +1,18 @@
+use crate::domain::models::CommandError;
+use crate::domain::services::FileService;
+
+/// Удаляет временный файл, если шаг обработки после `make_temp` завершился
+/// ошибкой, и возвращает эту ошибку. Если временный файл удалить не удалось,
+/// возвращается ошибка удаления.
+pub fn revert_on_err<F, C, T>(
+    file_service: &mut F,
+    result: Result<T, CommandError<F::Error, C>>,
+) -> Result<T, CommandError<F::Error, C>>
+where
+    F: FileService,
+{
+    result.map_err(|e| match file_service.revert() {
+        Ok(_) => e,
+        Err(err) => CommandError::FileServiceError(err),
+    })
+}
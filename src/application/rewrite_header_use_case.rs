@@ -0,0 +1,188 @@
+use super::file_start::read_file_start;
+use super::revert::revert_on_err;
+use crate::domain::models::{CancelToken, CommandError};
+use crate::domain::services::{BaseActions, FileService, HeaderService, TerminalService};
+
+/// Заменяет заголовок зашифрованного файла, тело копируется без изменений
+pub struct RewriteHeaderUseCase<F, H, T> {
+    cancel: CancelToken,
+    file_service: F,
+    header_service: H,
+    terminal: T,
+}
+
+impl<F, H, T> RewriteHeaderUseCase<F, H, T>
+where
+    F: FileService,
+    H: HeaderService,
+    T: TerminalService,
+{
+    pub fn new(file_service: F, header_service: H, terminal: T) -> Self {
+        Self {
+            cancel: CancelToken::default(),
+            file_service,
+            header_service,
+            terminal,
+        }
+    }
+
+    /// Токен отмены: после сигнала временный файл удаляется,
+    /// а исходный файл остаётся нетронутым
+    pub fn with_cancel(mut self, cancel: CancelToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+}
+
+impl<F, H, T> BaseActions for RewriteHeaderUseCase<F, H, T>
+where
+    F: FileService,
+    H: HeaderService,
+    T: TerminalService,
+{
+    type Error = CommandError<F::Error, H::Error>;
+    fn execute(&mut self) -> Result<(), Self::Error> {
+        self.file_service
+            .init_original()
+            .map_err(CommandError::FileServiceError)?;
+
+        let start = read_file_start(&mut self.file_service, |head| {
+            self.header_service.probe_header(head)
+        })?;
+        let Some(header) = start.header() else {
+            return Err(CommandError::NotEncrypted);
+        };
+
+        let new_header = self
+            .header_service
+            .rewrite(&header)
+            .map_err(CommandError::CryptoService)?;
+
+        self.file_service
+            .make_temp()
+            .map_err(CommandError::FileServiceError)?;
+
+        let result = self
+            .file_service
+            .write_chunk(new_header)
+            .map_err(CommandError::FileServiceError);
+        revert_on_err(&mut self.file_service, result)?;
+
+        loop {
+            revert_on_err(&mut self.file_service, self.cancel.check())?;
+
+            let result = self
+                .file_service
+                .read_chunk_original()
+                .map_err(CommandError::FileServiceError);
+            let chunk = revert_on_err(&mut self.file_service, result)?;
+            let Some(chunk) = chunk else {
+                break;
+            };
+
+            let result = self
+                .file_service
+                .write_chunk(chunk)
+                .map_err(CommandError::FileServiceError);
+            revert_on_err(&mut self.file_service, result)?;
+        }
+
+        revert_on_err(&mut self.file_service, self.cancel.check())?;
+
+        self.file_service
+            .commit()
+            .map_err(CommandError::FileServiceError)?;
+
+        self.terminal
+            .print_msg("Заголовок файла обновлён".to_string());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mock_service::{MockHeaderService, MockTerminal, MockedFileService};
+    use super::*;
+
+    #[test]
+    fn test_normal() {
+        let mut file_service = MockedFileService::new();
+        file_service.read_chunks = vec![b"encrypt_abc".to_vec()];
+        let mut header_service = MockHeaderService::new();
+        header_service.new_header = b"new_head".to_vec();
+
+        let mut use_case = RewriteHeaderUseCase::new(file_service, header_service, MockTerminal);
+        let result = use_case.execute();
+
+        let command_called = use_case.file_service.called_method;
+        let write_chunks = use_case.file_service.write_chunk;
+
+        assert!(result.is_ok());
+        assert_eq!(
+            command_called,
+            vec![
+                "init_original",
+                "read_exact_original",
+                "make_temp",
+                "write_chunk",
+                "read_chunk_original",
+                "write_chunk",
+                "read_chunk_original",
+                "commit"
+            ]
+        );
+        assert_eq!(write_chunks, vec![b"new_head".to_vec(), b"abc".to_vec()]);
+    }
+
+    //Проверяем, что при ошибке изменения заголовка временный файл не создаётся
+    #[test]
+    fn error_rewrite() {
+        let mut file_service = MockedFileService::new();
+        file_service.read_chunks = vec![b"encrypt_abc".to_vec()];
+        let mut header_service = MockHeaderService::new();
+        header_service.ok_rewrite = false;
+
+        let mut use_case = RewriteHeaderUseCase::new(file_service, header_service, MockTerminal);
+        let result = use_case.execute();
+
+        let command_called = use_case.file_service.called_method;
+
+        assert!(result.is_err());
+        assert!(!command_called.contains(&"make_temp"));
+        assert!(!command_called.contains(&"commit"));
+    }
+
+    //Проверяем, что файл короче заголовка не заменяется
+    #[test]
+    fn error_short_file() {
+        let mut file_service = MockedFileService::new();
+        file_service.read_chunks = vec![b"CRY".to_vec()];
+
+        let mut use_case =
+            RewriteHeaderUseCase::new(file_service, MockHeaderService::new(), MockTerminal);
+        let result = use_case.execute();
+
+        let command_called = use_case.file_service.called_method;
+
+        assert!(matches!(result, Err(CommandError::NotEncrypted)));
+        assert!(!command_called.contains(&"make_temp"));
+    }
+
+    //Проверяем, что при ошибке записи тела временный файл удаляется
+    #[test]
+    fn error_write() {
+        let mut file_service = MockedFileService::new();
+        file_service.read_chunks = vec![b"encrypt_abc".to_vec()];
+        file_service.ok_write_chunk = false;
+
+        let mut use_case =
+            RewriteHeaderUseCase::new(file_service, MockHeaderService::new(), MockTerminal);
+        let result = use_case.execute();
+
+        let command_called = use_case.file_service.called_method;
+
+        assert!(result.is_err());
+        assert_eq!(command_called.last(), Some(&"revert"));
+        assert!(!command_called.contains(&"commit"));
+    }
+}
@@ -1,7 +1,25 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
 use super::services::ConsoleError;
 pub enum CommandError<F, C> {
     FileServiceError(F),
     CryptoService(C),
+    /// Операция прервана сигналом, временный файл удалён
+    Cancelled,
+    /// `prepare` для уже зашифрованного файла
+    AlreadyEncrypted,
+    /// `decrypt` для незашифрованного файла
+    NotEncrypted,
+    /// Замена на месте файла устаревшего формата, в заголовке которого нет
+    /// проверки ключа, без `--force-legacy`
+    UncheckedLegacy,
+    /// `prepare --on-already error` для файла, который начинается
+    /// с заголовка устаревшего формата
+    AmbiguousLegacy,
+    /// Настройки не загружаются, текст ошибки загрузчика настроек
+    Config(String),
 }
 
 impl<F, C> ConsoleError for CommandError<F, C>
@@ -13,6 +31,62 @@ where
         match self {
             CommandError::CryptoService(c) => c.consol_log(),
             CommandError::FileServiceError(c) => c.consol_log(),
+            CommandError::Cancelled => "Операция прервана, исходный файл не изменён".to_string(),
+            CommandError::AlreadyEncrypted => {
+                "Файл уже зашифрован, для повторного шифрования укажите --on-already force"
+                    .to_string()
+            }
+            CommandError::NotEncrypted => "Файл не зашифрован".to_string(),
+            CommandError::UncheckedLegacy => {
+                "Файл устаревшего формата CRYPT1 не позволяет проверить ключ: с неверным ключом \
+                 он будет заменён мусором. Расшифруйте его в другой файл через decrypt -o \
+                 или укажите --force-legacy"
+                    .to_string()
+            }
+            CommandError::AmbiguousLegacy => {
+                "Файл начинается с CRYPT1: это файл устаревшего формата или данные с таким \
+                 началом. Проверьте его командой read, для шифрования укажите --on-already force"
+                    .to_string()
+            }
+            CommandError::Config(e) => format!("Настройки не загружаются: {e}"),
+        }
+    }
+}
+
+/// Результат проверки начала файла на заголовок зашифрованного файла
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeaderProbe {
+    /// Файл не начинается с заголовка
+    Plain,
+    /// Заголовок прочитан не целиком, нужно ещё столько байт
+    Incomplete(usize),
+    /// Прочитанные байты — заголовок целиком
+    Complete,
+    /// Прочитанные байты — заголовок устаревшего формата целиком. У него нет
+    /// версии и контрольной суммы, поэтому его не отличить от данных,
+    /// которые случайно начинаются с того же magic
+    Legacy,
+}
+
+/// Флаг отмены, который выставляет обработчик SIGINT/SIGTERM.
+/// Копии токена разделяют один флаг.
+#[derive(Debug, Default, Clone)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Возвращает `CommandError::Cancelled`, если операция отменена
+    pub fn check<F, C>(&self) -> Result<(), CommandError<F, C>> {
+        match self.is_cancelled() {
+            true => Err(CommandError::Cancelled),
+            false => Ok(()),
         }
     }
 }
@@ -22,24 +96,335 @@ pub enum Command {
     Prepare(String),
     Read(String),
     Decrypt(String),
+    /// Перешифровать файл или все файлы каталога ключом по умолчанию
+    Rekey(String),
+    /// Создать файл identity X25519 или симметричный ключ
+    Keygen(KeygenOptions, String),
+    /// Изменить или вывести получателей файла без перешифрования данных
+    Recipients(RecipientsAction, String),
+    /// Найти и разобрать временные файлы прерванных операций в каталоге
+    Recover(String),
+    /// Вывести действующие настройки и источник каждого значения
+    ShowConfig,
+}
+
+pub enum RecipientsAction {
+    Add(Vec<String>),
+    Remove(Vec<String>),
+    List,
+}
+
+/// Состояние временного файла, оставшегося после прерванной операции
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TempStatus {
+    /// Результат записан полностью
+    Complete,
+    /// Запись оборвалась на середине
+    Partial,
+    /// Проверить не удалось
+    Unknown,
+}
+
+/// Что сделать с временным файлом прерванной операции
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecoverAction {
+    /// Завершить замену исходного файла временным
+    Replace,
+    /// Удалить временный файл
+    Delete,
+    /// Сохранить временный файл рядом под постоянным именем
+    Keep,
+    /// Ничего не делать
+    Skip,
+}
+
+impl RecoverAction {
+    /// Безопасное действие по умолчанию: временный файл заменяет исходный,
+    /// только если он записан полностью, и удаляется, только если исходный цел
+    pub fn recommended(status: TempStatus, original_exists: bool) -> Self {
+        match (status, original_exists) {
+            (TempStatus::Complete, _) => Self::Replace,
+            (TempStatus::Partial, true) => Self::Delete,
+            (TempStatus::Partial, false) => Self::Keep,
+            (TempStatus::Unknown, _) => Self::Skip,
+        }
+    }
+}
+
+/// Формат симметричного ключа, создаваемого командой keygen
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum KeyFormat {
+    #[default]
+    Hex,
+    Base64,
+    /// Файл с байтами ключа вместо файла настроек
+    Raw,
+}
+
+/// Что делать с файлом, который уже зашифрован (для `prepare`)
+/// или ещё не зашифрован (для `decrypt`)
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum AlreadyProcessed {
+    /// Оставить файл без изменений
+    #[default]
+    Skip,
+    /// Завершить обработку файла ошибкой
+    Error,
+    /// Обработать файл без проверки
+    Force,
+}
+
+/// Вид вывода команды read
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum ReadFormat {
+    /// Байты файла без изменений
+    #[default]
+    Raw,
+    /// Текст UTF-8, некорректные последовательности заменяются на `�`
+    Text,
+    /// Шестнадцатеричный дамп со смещениями
+    Hex,
+}
+
+/// Флаги команды keygen
+#[derive(Debug, Default, Clone)]
+pub struct KeygenOptions {
+    /// Создать симметричный ключ вместо identity X25519
+    pub symmetric: bool,
+    /// Имя ключа в связке
+    pub name: Option<String>,
+    pub format: KeyFormat,
+    /// Набор алгоритмов, для которого создаётся ключ
+    pub suite: Option<String>,
+}
+
+/// Атрибуты исходного файла, которые сохраняются после замены его
+/// зашифрованной или расшифрованной копией
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PreserveOptions {
+    /// Права доступа
+    pub mode: bool,
+    /// Владелец и группа
+    pub owner: bool,
+    /// Время доступа и изменения
+    pub timestamps: bool,
+    /// Расширенные атрибуты
+    pub xattrs: bool,
+}
+
+impl Default for PreserveOptions {
+    fn default() -> Self {
+        Self {
+            mode: true,
+            owner: true,
+            timestamps: true,
+            xattrs: true,
+        }
+    }
+}
+
+impl PreserveOptions {
+    pub fn none() -> Self {
+        Self {
+            mode: false,
+            owner: false,
+            timestamps: false,
+            xattrs: false,
+        }
+    }
+}
+
+/// Флаги командной строки
+#[derive(Debug, Default, Clone)]
+pub struct CliOptions {
+    /// Запросить пароль в терминале, если не задан `CRYPTO_PASSPHRASE`
+    pub passphrase: bool,
+    /// Имя ключа из связки для шифрования вместо ключа по умолчанию
+    pub key_id: Option<String>,
+    /// Открытые ключи получателей из `--recipient`
+    pub recipients: Vec<String>,
+    /// Файл identity из `--identity`
+    pub identity: Option<String>,
+    pub keygen: KeygenOptions,
+    /// Обработать все файлы каталога
+    pub recursive: bool,
+    /// Шаблоны файлов каталога, которые нужно обработать
+    pub include: Vec<String>,
+    /// Шаблоны файлов и каталогов, которые нужно пропустить
+    pub exclude: Vec<String>,
+    pub preserve: PreserveOptions,
+    /// Выполнять рекомендуемые действия без вопросов
+    pub assume_yes: bool,
+    /// Каталог для временных файлов из `--temp-dir`
+    pub temp_dir: Option<String>,
+    /// Файл результата вместо замены исходного
+    pub output: Option<String>,
+    /// Перезаписать существующий файл `output`
+    pub force: bool,
+    /// Заменять на месте файлы `CRYPT1`, ключ которых нельзя проверить
+    pub force_legacy: bool,
+    /// Ключ из связки, которым `rekey` расшифровывает файлы без отпечатка ключа
+    pub from_key_id: Option<String>,
+    /// Ключ из связки, которым `rekey` шифрует файлы
+    pub to_key_id: Option<String>,
+    /// `rekey` шифрует файлы новым паролем
+    pub to_passphrase: bool,
+    /// `rekey` перешифровывает файл целиком, даже если можно заменить
+    /// только заголовок
+    pub full_rekey: bool,
+    /// Политика `--on-already` для `prepare` и `decrypt`
+    pub on_already: AlreadyProcessed,
+    /// Вид вывода команды read из `--text` или `--hex`
+    pub read_format: ReadFormat,
+    /// Файл настроек из `--config` вместо поиска
+    pub config: Option<String>,
+    /// Профиль настроек из `--profile`
+    pub profile: Option<String>,
 }
 
 /// Настройки системы
-#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[derive(Default, Clone, serde::Deserialize, serde::Serialize)]
 pub struct Credentials {
-    #[serde(deserialize_with = "hex_vec::deserialize")]
-    pub key: Vec<u8>,
+    #[serde(default, deserialize_with = "hex_vec::deserialize")]
+    pub key: Option<Vec<u8>>,
+    /// Связка именованных ключей
+    #[serde(default, deserialize_with = "hex_vec::deserialize_map")]
+    pub keys: BTreeMap<String, Vec<u8>>,
+    /// Имя ключа из `keys`, которым шифруются новые файлы
+    #[serde(default)]
+    pub default_key: Option<String>,
+    #[serde(default)]
+    pub passphrase: Option<String>,
+    /// Новый пароль для `rekey --to-passphrase` из `CRYPTO_NEW_PASSPHRASE`
+    #[serde(default)]
+    pub new_passphrase: Option<String>,
+    /// Размер блока в байтах. Допускает единицы (`64KiB`), поэтому
+    /// разбирается и проверяется отдельно при загрузке настроек
+    #[serde(skip)]
     pub chunk_size: usize,
+    #[serde(default)]
+    pub kdf: KdfSettings,
+    /// Открытые ключи X25519, для которых шифруются новые файлы
+    #[serde(default)]
+    pub recipients: Vec<String>,
+    /// Путь к файлу identity с секретными ключами X25519
+    #[serde(default)]
+    pub identity: Option<String>,
+    /// Секретные ключи, прочитанные из файла `identity`
+    #[serde(skip)]
+    pub identities: Vec<String>,
+    /// Каталог для временных файлов вместо каталога исходного файла
+    #[serde(default)]
+    pub temp_dir: Option<String>,
+}
+
+/// Ключи, пароли и секретные ключи identity скрываются так же,
+/// как в `config show`
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const HIDDEN: &str = "<скрыт>";
+        let hide = |secret: &Option<String>| secret.as_ref().map(|_| HIDDEN);
+
+        f.debug_struct("Credentials")
+            .field("key", &self.key.as_ref().map(|_| HIDDEN))
+            .field(
+                "keys",
+                &self
+                    .keys
+                    .keys()
+                    .map(|name| (name, HIDDEN))
+                    .collect::<BTreeMap<_, _>>(),
+            )
+            .field("default_key", &self.default_key)
+            .field("passphrase", &hide(&self.passphrase))
+            .field("new_passphrase", &hide(&self.new_passphrase))
+            .field("chunk_size", &self.chunk_size)
+            .field("kdf", &self.kdf)
+            .field("recipients", &self.recipients)
+            .field("identity", &self.identity)
+            .field("identities", &vec![HIDDEN; self.identities.len()])
+            .field("temp_dir", &self.temp_dir)
+            .finish()
+    }
+}
+
+/// Параметры Argon2id для новых файлов. Незаданные в `[kdf]` параметры
+/// берутся по умолчанию
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct KdfSettings {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfSettings {
+    fn default() -> Self {
+        Self {
+            memory_kib: 64 * 1024,
+            iterations: 3,
+            parallelism: 1,
+        }
+    }
 }
 
 mod hex_vec {
+    use base64ct::{Base64, Encoding};
     use serde::Deserialize;
+    use std::collections::BTreeMap;
+
+    /// Ключ в hex или в base64 с префиксом `base64:`
+    fn decode(s: &str) -> Result<Vec<u8>, String> {
+        match s.strip_prefix("base64:") {
+            Some(b64) => Base64::decode_vec(b64).map_err(|e| e.to_string()),
+            None => hex::decode(s).map_err(|e| e.to_string()),
+        }
+    }
 
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
-        hex::decode(&s).map_err(serde::de::Error::custom)
+        decode(&s).map(Some).map_err(serde::de::Error::custom)
+    }
+
+    pub fn deserialize_map<'de, D>(deserializer: D) -> Result<BTreeMap<String, Vec<u8>>, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        BTreeMap::<String, String>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(name, s)| {
+                decode(&s)
+                    .map(|key| (name, key))
+                    .map_err(serde::de::Error::custom)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Проверяем, что отладочный вывод настроек не раскрывает ключи и пароли
+    #[test]
+    fn test_credentials_debug_hides_secrets() {
+        let credentials = Credentials {
+            key: Some(vec![0xab; 32]),
+            keys: BTreeMap::from([("work".to_string(), vec![0xcd; 32])]),
+            passphrase: Some("old secret".into()),
+            new_passphrase: Some("new secret".into()),
+            identities: vec!["AGE-SECRET-KEY-1SECRET".into()],
+            ..Default::default()
+        };
+
+        let debug = format!("{credentials:?}");
+
+        assert!(debug.contains("work"));
+        for secret in ["171", "205", "old secret", "new secret", "SECRET-KEY"] {
+            assert!(!debug.contains(secret), "{secret}");
+        }
     }
 }
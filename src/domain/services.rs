@@ -1,23 +1,52 @@
+use super::models::HeaderProbe;
+
 // Отвечает за чтение файла по частям и удаление его
 pub trait FileService {
     type Error: ConsoleError;
     /// Создает временный файл с исходными данными
     fn init_original(&mut self) -> Result<&mut Self, Self::Error>;
     fn make_temp(&mut self) -> Result<&mut Self, Self::Error>;
-    fn rename_temp_as_original(&mut self) -> Result<&mut Self, Self::Error>;
-    fn delete_original(&mut self) -> Result<&mut Self, Self::Error>;
+    /// Сбрасывает временный файл на диск и атомарно заменяет им исходный.
+    /// При ошибке до замены исходный файл не меняется, а временный удаляется.
+    fn commit(&mut self) -> Result<&mut Self, Self::Error>;
     fn revert(&mut self) -> Result<&mut Self, Self::Error>;
 
-    fn read_chunk_original(&mut self) -> Vec<u8>;
+    /// Читает следующую часть исходного файла, `None` — конец файла
+    fn read_chunk_original(&mut self) -> Result<Option<Vec<u8>>, Self::Error>;
+    /// Читает ровно `len` байт исходного файла; меньше — только в конце файла
+    fn read_exact_original(&mut self, len: usize) -> Result<Vec<u8>, Self::Error>;
     fn write_chunk(&mut self, chunk: Vec<u8>) -> Result<(), Self::Error>;
 }
 
-/// Отвечает за шифрование и дешифрование данных
+/// Отвечает за шифрование и дешифрование данных.
+///
+/// Заголовок файла читается отдельно до потоковой обработки, поэтому
+/// `decrypt` получает только тело файла, а части могут быть любого размера.
 pub trait CryptoService {
     type Error: ConsoleError;
-    fn is_encrypt(&mut self, chunk: &Vec<u8>) -> Result<bool, Self::Error>;
+    /// Проверяет, начинается ли файл с заголовка, по уже прочитанному началу
+    fn probe_header(&self, head: &[u8]) -> Result<HeaderProbe, Self::Error>;
+    /// Разбирает заголовок целиком и готовит расшифровку тела файла
+    fn init_decrypt(&mut self, header: &[u8]) -> Result<(), Self::Error>;
+    /// Шифрует часть файла; первый результат начинается с заголовка
     fn encrypt(&mut self, chunk: Vec<u8>) -> Result<Vec<u8>, Self::Error>;
     fn decrypt(&mut self, chunk: Vec<u8>) -> Result<Vec<u8>, Self::Error>;
+    /// Завершает поток: возвращает последние данные и проверяет целостность.
+    /// Если при шифровании частей не было, возвращает заголовок пустого файла
+    fn finish(&mut self) -> Result<Vec<u8>, Self::Error>;
+}
+
+/// Отвечает за изменение заголовка зашифрованного файла без перешифрования данных
+pub trait HeaderService {
+    type Error: ConsoleError;
+    /// Проверяет, начинается ли файл с заголовка, по уже прочитанному началу
+    fn probe_header(&self, head: &[u8]) -> Result<HeaderProbe, Self::Error>;
+    /// Проверяет по заголовку, можно ли заменить только его
+    fn can_rewrite(&mut self, header: &[u8]) -> Result<bool, Self::Error>;
+    /// Возвращает новый заголовок; тело файла копируется без изменений
+    fn rewrite(&mut self, header: &[u8]) -> Result<Vec<u8>, Self::Error>;
+    /// Описывает способы получить ключ данных из заголовка
+    fn describe(&mut self, header: &[u8]) -> Result<Vec<String>, Self::Error>;
 }
 
 /// Отвечает за строковое представление ошибки
@@ -36,4 +65,6 @@ pub trait TerminalService {
     fn print_error_msg(&self, msg: String);
     fn print_chunk(&self, value: Vec<u8>);
     fn print_message_in_line(&self, msg: String);
+    /// Задаёт вопрос и возвращает ответ или `None`, если ответить некому
+    fn ask(&self, question: String) -> Option<String>;
 }
@@ -0,0 +1,258 @@
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use rand::RngCore;
+
+use super::crypto_service::{CryptoError, key_check, verify_key};
+use super::header::{FileHeader, TAG_KEY_CHECK, TAG_NONCE};
+
+const NONCE_PREFIX_LEN: usize = 7;
+const TAG_LEN: usize = 16;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Encrypt,
+    Decrypt,
+}
+
+/// Шифрование AES-256-GCM в режиме STREAM.
+///
+/// Данные делятся на сегменты фиксированного размера, каждый сегмент
+/// запечатывается отдельно. Nonce сегмента состоит из случайного префикса,
+/// номера сегмента и флага последнего сегмента, поэтому подмена, перестановка
+/// и усечение сегментов обнаруживаются при расшифровке. Неизменяемая часть
+/// заголовка передаётся как associated data каждого сегмента, поэтому подмена
+/// её полей с пересчётом CRC32 тоже обнаруживается.
+pub struct AesGcmCryptoService {
+    cipher: Aes256Gcm,
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    segment_size: usize,
+    counter: u32,
+    buffer: Vec<u8>,
+    aad: Vec<u8>,
+    mode: Mode,
+}
+
+impl AesGcmCryptoService {
+    /// Создаёт сервис со случайным префиксом nonce и дописывает его в заголовок.
+    /// Размер сегмента берётся из `chunk_size` заголовка.
+    pub fn for_encrypt(key: &[u8; 32], header: &mut FileHeader) -> Result<Self, CryptoError> {
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_LEN];
+        rand::rng().fill_bytes(&mut nonce_prefix);
+        header.fields.push((TAG_NONCE, nonce_prefix.to_vec()));
+        header.fields.push((TAG_KEY_CHECK, key_check(key).to_vec()));
+
+        Self::new(key, nonce_prefix, header, Mode::Encrypt)
+    }
+
+    /// Создаёт сервис с префиксом nonce и размером сегмента из заголовка файла.
+    /// Неверный ключ отклоняется по проверочному значению из заголовка.
+    pub fn for_decrypt(key: &[u8; 32], header: &FileHeader) -> Result<Self, CryptoError> {
+        verify_key(key, header)?;
+
+        let nonce = header
+            .field(TAG_NONCE)
+            .filter(|nonce| nonce.len() == NONCE_PREFIX_LEN)
+            .ok_or(CryptoError::InvalidHeader("Некорректный nonce".into()))?;
+
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_LEN];
+        nonce_prefix.copy_from_slice(nonce);
+
+        Self::new(key, nonce_prefix, header, Mode::Decrypt)
+    }
+
+    fn new(
+        key: &[u8; 32],
+        nonce_prefix: [u8; NONCE_PREFIX_LEN],
+        header: &FileHeader,
+        mode: Mode,
+    ) -> Result<Self, CryptoError> {
+        if header.chunk_size == 0 {
+            return Err(CryptoError::InvalidHeader("Нулевой размер сегмента".into()));
+        }
+
+        Ok(Self {
+            cipher: Aes256Gcm::new(key.into()),
+            nonce_prefix,
+            segment_size: header.chunk_size as usize,
+            counter: 0,
+            buffer: Vec::new(),
+            aad: header.associated_data()?,
+            mode,
+        })
+    }
+
+    fn check_mode(&self, mode: Mode) -> Result<(), CryptoError> {
+        if self.mode != mode {
+            return Err(CryptoError::AesError(
+                "Шифрование и расшифровка в одном потоке".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn nonce(&self, is_last: bool) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[..NONCE_PREFIX_LEN].copy_from_slice(&self.nonce_prefix);
+        nonce[NONCE_PREFIX_LEN..11].copy_from_slice(&self.counter.to_be_bytes());
+        nonce[11] = is_last as u8;
+        nonce
+    }
+
+    fn next_counter(&mut self) -> Result<(), CryptoError> {
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or(CryptoError::AesError("Превышено число сегментов".into()))?;
+        Ok(())
+    }
+
+    fn seal_segment(&mut self, segment: &[u8], is_last: bool) -> Result<Vec<u8>, CryptoError> {
+        let nonce = self.nonce(is_last);
+        let payload = Payload {
+            msg: segment,
+            aad: &self.aad,
+        };
+        let sealed = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|e| CryptoError::AesError(format!("{e:?}")))?;
+        self.next_counter()?;
+        Ok(sealed)
+    }
+
+    fn open_segment(&mut self, segment: &[u8], is_last: bool) -> Result<Vec<u8>, CryptoError> {
+        let nonce = self.nonce(is_last);
+        let payload = Payload {
+            msg: segment,
+            aad: &self.aad,
+        };
+        let opened = self
+            .cipher
+            .decrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| CryptoError::AuthenticationFailed)?;
+        self.next_counter()?;
+        Ok(opened)
+    }
+
+    pub fn encrypt(&mut self, chunk: Vec<u8>) -> Result<Vec<u8>, CryptoError> {
+        self.check_mode(Mode::Encrypt)?;
+        self.buffer.extend(chunk);
+
+        // Последний сегмент запечатывается только в finish, поэтому
+        // в буфере всегда остаётся хотя бы один байт
+        let mut out = Vec::new();
+        while self.buffer.len() > self.segment_size {
+            let rest = self.buffer.split_off(self.segment_size);
+            let segment = std::mem::replace(&mut self.buffer, rest);
+            out.extend(self.seal_segment(&segment, false)?);
+        }
+        Ok(out)
+    }
+
+    pub fn decrypt(&mut self, chunk: Vec<u8>) -> Result<Vec<u8>, CryptoError> {
+        self.check_mode(Mode::Decrypt)?;
+        self.buffer.extend(chunk);
+
+        let sealed_size = self.segment_size + TAG_LEN;
+        let mut out = Vec::new();
+        while self.buffer.len() > sealed_size {
+            let rest = self.buffer.split_off(sealed_size);
+            let segment = std::mem::replace(&mut self.buffer, rest);
+            out.extend(self.open_segment(&segment, false)?);
+        }
+        Ok(out)
+    }
+
+    pub fn finish(&mut self) -> Result<Vec<u8>, CryptoError> {
+        let segment = std::mem::take(&mut self.buffer);
+        match self.mode {
+            Mode::Encrypt => self.seal_segment(&segment, true),
+            Mode::Decrypt => {
+                if segment.len() < TAG_LEN {
+                    return Err(CryptoError::AuthenticationFailed);
+                }
+                self.open_segment(&segment, true)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infrastructure::header::CipherSuite;
+
+    const KEY: &[u8; 32] = b"12345678901234567890123456789012";
+    const SEALED_SIZE: usize = 4 + TAG_LEN;
+
+    fn encrypt_all(chunks: &[&[u8]]) -> (FileHeader, Vec<u8>) {
+        let mut header = FileHeader::new(CipherSuite::Aes256GcmStream, 4);
+        let mut enc = AesGcmCryptoService::for_encrypt(KEY, &mut header).unwrap();
+        let mut out = Vec::new();
+        for chunk in chunks {
+            out.extend(enc.encrypt(chunk.to_vec()).unwrap());
+        }
+        out.extend(enc.finish().unwrap());
+        (header, out)
+    }
+
+    fn decrypt_all(header: &FileHeader, data: Vec<u8>) -> Result<Vec<u8>, CryptoError> {
+        let mut dec = AesGcmCryptoService::for_decrypt(KEY, header)?;
+        let mut out = Vec::new();
+        for chunk in data.chunks(5) {
+            out.extend(dec.decrypt(chunk.to_vec())?);
+        }
+        out.extend(dec.finish()?);
+        Ok(out)
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_chunk() {
+        let (header, encrypted) = encrypt_all(&[b"Bla bla bla ", b"Bob Bob Bob"]);
+        let decrypted = decrypt_all(&header, encrypted).unwrap();
+
+        assert_eq!(decrypted, b"Bla bla bla Bob Bob Bob".to_vec());
+    }
+
+    #[test]
+    fn test_wrong_key() {
+        let (header, _) = encrypt_all(&[b"Bla bla bla "]);
+
+        let result = AesGcmCryptoService::for_decrypt(&[0; 32], &header);
+
+        assert!(matches!(result, Err(CryptoError::WrongKey)));
+    }
+
+    #[test]
+    fn test_tampered_data() {
+        let (header, mut encrypted) = encrypt_all(&[b"Bla bla bla ", b"Bob Bob Bob"]);
+        encrypted[1] ^= 1;
+
+        let result = decrypt_all(&header, encrypted);
+
+        assert!(matches!(result, Err(CryptoError::AuthenticationFailed)));
+    }
+
+    #[test]
+    fn test_truncated_data() {
+        let (header, encrypted) = encrypt_all(&[b"Bla bla bla ", b"Bob Bob Bob"]);
+        let truncated = encrypted[..SEALED_SIZE * 2].to_vec();
+
+        let result = decrypt_all(&header, truncated);
+
+        assert!(matches!(result, Err(CryptoError::AuthenticationFailed)));
+    }
+
+    #[test]
+    fn test_reordered_segments() {
+        let (header, encrypted) = encrypt_all(&[b"Bla bla bla ", b"Bob Bob Bob"]);
+
+        let mut reordered = encrypted[SEALED_SIZE..SEALED_SIZE * 2].to_vec();
+        reordered.extend_from_slice(&encrypted[..SEALED_SIZE]);
+        reordered.extend_from_slice(&encrypted[SEALED_SIZE * 2..]);
+
+        let result = decrypt_all(&header, reordered);
+
+        assert!(matches!(result, Err(CryptoError::AuthenticationFailed)));
+    }
+}
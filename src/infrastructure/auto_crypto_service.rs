@@ -0,0 +1,333 @@
+use crate::domain::models::{Credentials, HeaderProbe};
+use crate::domain::services::CryptoService;
+
+use super::aead_crypto_service::AesGcmCryptoService;
+use super::crypto_service::{AesCtrCryptoService, CryptoError};
+use super::header::{CipherSuite, FileHeader};
+use super::key_resolver::KeyResolver;
+
+enum SuiteService {
+    Ctr(AesCtrCryptoService),
+    Gcm(AesGcmCryptoService),
+}
+
+impl SuiteService {
+    fn encrypt(&mut self, chunk: Vec<u8>) -> Result<Vec<u8>, CryptoError> {
+        match self {
+            Self::Ctr(ctr) => ctr.encrypt(chunk),
+            Self::Gcm(gcm) => gcm.encrypt(chunk),
+        }
+    }
+
+    fn decrypt(&mut self, chunk: Vec<u8>) -> Result<Vec<u8>, CryptoError> {
+        match self {
+            Self::Ctr(ctr) => ctr.decrypt(chunk),
+            Self::Gcm(gcm) => gcm.decrypt(chunk),
+        }
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>, CryptoError> {
+        match self {
+            Self::Ctr(ctr) => ctr.finish(),
+            Self::Gcm(gcm) => gcm.finish(),
+        }
+    }
+}
+
+/// Разбирает и записывает заголовок файла, получает ключ данных
+/// и выбирает реализацию по набору алгоритмов из заголовка.
+/// Заголовок для расшифровки передаётся в `init_decrypt` отдельно от тела.
+///
+/// Новые файлы всегда шифруются AES-256-GCM, а файлы `CRYPT1`
+/// и `CRYPT2` с AES-256-CTR по-прежнему расшифровываются.
+pub struct AutoCryptoService {
+    chunk_size: usize,
+    keys: KeyResolver,
+    inner: Option<SuiteService>,
+}
+
+impl From<Credentials> for AutoCryptoService {
+    fn from(credentials: Credentials) -> Self {
+        Self {
+            chunk_size: credentials.chunk_size,
+            keys: KeyResolver::from(credentials),
+            inner: None,
+        }
+    }
+}
+
+impl AutoCryptoService {
+    fn start_encrypt(&mut self) -> Result<Vec<u8>, CryptoError> {
+        let chunk_size = u32::try_from(self.chunk_size)
+            .map_err(|_| CryptoError::AesError("Слишком большой размер блока".into()))?;
+
+        let mut header = FileHeader::new(CipherSuite::Aes256GcmStream, chunk_size);
+        let key = self.keys.file_key_for_encrypt(&mut header)?;
+        let gcm = AesGcmCryptoService::for_encrypt(&key, &mut header)?;
+
+        self.inner = Some(SuiteService::Gcm(gcm));
+        header.encode()
+    }
+
+    fn inner(&mut self) -> Result<&mut SuiteService, CryptoError> {
+        self.inner
+            .as_mut()
+            .ok_or(CryptoError::AesError("Cipher not initialized".into()))
+    }
+
+    fn start_decrypt(&mut self, header: &FileHeader) -> Result<(), CryptoError> {
+        let key = self.keys.file_key_for_decrypt(header)?;
+        let inner = match header.suite {
+            CipherSuite::Aes256GcmStream => {
+                SuiteService::Gcm(AesGcmCryptoService::for_decrypt(&key, header)?)
+            }
+            CipherSuite::Aes256Ctr => {
+                SuiteService::Ctr(AesCtrCryptoService::for_decrypt(&key, header)?)
+            }
+        };
+
+        self.inner = Some(inner);
+        Ok(())
+    }
+}
+
+impl CryptoService for AutoCryptoService {
+    type Error = CryptoError;
+
+    fn probe_header(&self, head: &[u8]) -> Result<HeaderProbe, Self::Error> {
+        FileHeader::probe(head)
+    }
+
+    fn init_decrypt(&mut self, header: &[u8]) -> Result<(), Self::Error> {
+        self.start_decrypt(&FileHeader::decode(header)?)
+    }
+
+    fn encrypt(&mut self, chunk: Vec<u8>) -> Result<Vec<u8>, Self::Error> {
+        let mut out = Vec::new();
+        if self.inner.is_none() {
+            out = self.start_encrypt()?;
+        }
+
+        out.extend(self.inner()?.encrypt(chunk)?);
+        Ok(out)
+    }
+
+    fn decrypt(&mut self, chunk: Vec<u8>) -> Result<Vec<u8>, Self::Error> {
+        self.inner()?.decrypt(chunk)
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>, Self::Error> {
+        // Пустой файл: `encrypt` не вызывался, заголовок пишется здесь,
+        // чтобы зашифрованный файл можно было отличить от пустого
+        let mut out = Vec::new();
+        if self.inner.is_none() {
+            out = self.start_encrypt()?;
+        }
+
+        out.extend(self.inner()?.finish()?);
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::models::KdfSettings;
+    use std::collections::BTreeMap;
+
+    const KEY: &[u8; 32] = b"12345678901234567890123456789012";
+
+    /// Параметры Argon2id, с которыми тесты с паролем выполняются быстро
+    const FAST_KDF: KdfSettings = KdfSettings {
+        memory_kib: 64,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    /// Читает заголовок порциями из `probe_header`, тело — частями `chunk_size`
+    fn decrypt_all(
+        dec: &mut AutoCryptoService,
+        data: &[u8],
+        chunk_size: usize,
+    ) -> Result<Vec<u8>, CryptoError> {
+        let mut header_len = 0;
+        while let HeaderProbe::Incomplete(len) = dec.probe_header(&data[..header_len])? {
+            header_len += len;
+        }
+        dec.init_decrypt(&data[..header_len])?;
+
+        let mut out = Vec::new();
+        for chunk in data[header_len..].chunks(chunk_size) {
+            out.extend(dec.decrypt(chunk.to_vec())?);
+        }
+        out.extend(dec.finish()?);
+        Ok(out)
+    }
+
+    fn round_trip(enc: Credentials, dec: Credentials) -> Result<Vec<u8>, CryptoError> {
+        let mut enc = AutoCryptoService::from(enc);
+        let mut encrypted = enc.encrypt(b"Bla bla bla Bob Bob Bob".to_vec())?;
+        encrypted.extend(enc.finish()?);
+
+        decrypt_all(&mut AutoCryptoService::from(dec), &encrypted, 3)
+    }
+
+    #[test]
+    fn test_round_trip_with_key() {
+        let creds = Credentials {
+            key: Some(KEY.to_vec()),
+            chunk_size: 8,
+            ..Default::default()
+        };
+
+        let decrypted = round_trip(creds.clone(), creds).unwrap();
+
+        assert_eq!(decrypted, b"Bla bla bla Bob Bob Bob".to_vec());
+    }
+
+    //Проверяем, что заголовок не зависит от размера части: файл с частями
+    //в один байт шифруется и читается по одному байту
+    #[test]
+    fn test_chunk_size_one() {
+        let creds = Credentials {
+            key: Some(KEY.to_vec()),
+            chunk_size: 1,
+            ..Default::default()
+        };
+        let mut enc = AutoCryptoService::from(creds.clone());
+        let mut encrypted = Vec::new();
+        for byte in b"Bla bla" {
+            encrypted.extend(enc.encrypt(vec![*byte]).unwrap());
+        }
+        encrypted.extend(enc.finish().unwrap());
+
+        let decrypted = decrypt_all(&mut AutoCryptoService::from(creds), &encrypted, 1).unwrap();
+
+        assert_eq!(decrypted, b"Bla bla".to_vec());
+    }
+
+    //Проверяем, что пустой файл получает заголовок и тег целостности,
+    //а усечённый до заголовка файл не расшифровывается
+    #[test]
+    fn test_empty_file() {
+        let creds = Credentials {
+            key: Some(KEY.to_vec()),
+            chunk_size: 8,
+            ..Default::default()
+        };
+        let mut enc = AutoCryptoService::from(creds.clone());
+        let encrypted = enc.finish().unwrap();
+
+        let mut dec = AutoCryptoService::from(creds.clone());
+        assert_eq!(dec.probe_header(&encrypted).unwrap(), HeaderProbe::Complete);
+        let decrypted = decrypt_all(&mut dec, &encrypted, 4).unwrap();
+        assert!(decrypted.is_empty());
+
+        let header = FileHeader::parse(&encrypted).unwrap().unwrap().1;
+        let mut dec = AutoCryptoService::from(creds);
+        let result = decrypt_all(&mut dec, &encrypted[..header], 4);
+        assert!(matches!(result, Err(CryptoError::AuthenticationFailed)));
+    }
+
+    //Проверяем, что изменение заголовка с пересчётом CRC32 обнаруживается
+    #[test]
+    fn test_tampered_header() {
+        let creds = Credentials {
+            key: Some(KEY.to_vec()),
+            chunk_size: 8,
+            ..Default::default()
+        };
+        let mut enc = AutoCryptoService::from(creds.clone());
+        let mut encrypted = enc.encrypt(b"Bla bla bla".to_vec()).unwrap();
+        encrypted.extend(enc.finish().unwrap());
+        let (mut header, header_len) = FileHeader::parse(&encrypted).unwrap().unwrap();
+
+        header.fields.push((0x7f, b"tampered".to_vec()));
+        let mut tampered = header.encode().unwrap();
+        tampered.extend_from_slice(&encrypted[header_len..]);
+
+        let mut dec = AutoCryptoService::from(creds);
+        let result = decrypt_all(&mut dec, &tampered, 4);
+        assert!(matches!(result, Err(CryptoError::AuthenticationFailed)));
+    }
+
+    #[test]
+    fn test_round_trip_with_passphrase() {
+        let creds = Credentials {
+            passphrase: Some("correct horse battery staple".into()),
+            chunk_size: 8,
+            kdf: FAST_KDF,
+            ..Default::default()
+        };
+
+        let decrypted = round_trip(creds.clone(), creds).unwrap();
+
+        assert_eq!(decrypted, b"Bla bla bla Bob Bob Bob".to_vec());
+    }
+
+    #[test]
+    fn test_wrong_passphrase() {
+        let enc = Credentials {
+            key: Some(KEY.to_vec()),
+            passphrase: Some("correct horse battery staple".into()),
+            chunk_size: 8,
+            kdf: FAST_KDF,
+            ..Default::default()
+        };
+        let mut dec = enc.clone();
+        dec.passphrase = Some("incorrect horse".into());
+
+        let result = round_trip(enc, dec);
+
+        assert!(matches!(result, Err(CryptoError::WrongKey)));
+    }
+
+    #[test]
+    fn test_passphrase_file_without_passphrase() {
+        let dec = Credentials {
+            key: Some(KEY.to_vec()),
+            chunk_size: 8,
+            ..Default::default()
+        };
+        let mut enc = dec.clone();
+        enc.passphrase = Some("correct horse battery staple".into());
+        enc.kdf = FAST_KDF;
+
+        let result = round_trip(enc, dec);
+
+        assert!(matches!(result, Err(CryptoError::KeyUnavailable(_))));
+    }
+
+    // Файл, зашифрованный старым ключом, расшифровывается после смены ключа по умолчанию
+    #[test]
+    fn test_key_selected_by_key_id() {
+        let enc = Credentials {
+            keys: BTreeMap::from([("old".to_string(), vec![1; 32])]),
+            chunk_size: 8,
+            ..Default::default()
+        };
+        let mut dec = enc.clone();
+        dec.keys.insert("new".to_string(), vec![2; 32]);
+        dec.default_key = Some("new".into());
+
+        let decrypted = round_trip(enc, dec).unwrap();
+
+        assert_eq!(decrypted, b"Bla bla bla Bob Bob Bob".to_vec());
+    }
+
+    //Проверяем, что файл, ключа которого нет в связке, даёт ошибку неверного ключа
+    #[test]
+    fn test_unknown_key_id() {
+        let enc = Credentials {
+            key: Some(KEY.to_vec()),
+            chunk_size: 8,
+            ..Default::default()
+        };
+        let mut dec = enc.clone();
+        dec.key = Some(vec![9; 32]);
+
+        let result = round_trip(enc, dec);
+
+        assert!(matches!(result, Err(CryptoError::WrongKey)));
+    }
+}
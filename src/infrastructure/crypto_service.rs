@@ -1,19 +1,25 @@
 use aes::Aes256;
 use cipher::{KeyIvInit, StreamCipher};
 use ctr::Ctr128BE;
-use rand::RngCore;
+use sha2::{Digest, Sha256};
 
-use crate::domain::models::Credentials;
-use crate::domain::services::{ConsoleError, CryptoService};
+use crate::domain::services::ConsoleError;
+
+use super::header::{FileHeader, TAG_KEY_CHECK, TAG_NONCE};
 
 type Aes256Ctr = Ctr128BE<Aes256>;
 
-const MAGIC: &[u8] = b"CRYPT1";
+const KEY_CHECK_LEN: usize = 16;
 
 #[derive(Debug)]
 pub enum CryptoError {
     InvalidHeader(String),
     AesError(String),
+    AuthenticationFailed,
+    KeyUnavailable(String),
+    KdfError(String),
+    InvalidKey(String),
+    WrongKey,
 }
 
 impl ConsoleError for CryptoError {
@@ -21,145 +27,129 @@ impl ConsoleError for CryptoError {
         match self {
             CryptoError::InvalidHeader(e) => format!("Некорректный заголовок: {e}"),
             CryptoError::AesError(e) => format!("Ошибка AES: {e}"),
+            CryptoError::AuthenticationFailed => {
+                "Ошибка проверки целостности: данные повреждены, усечены или переставлены"
+                    .to_string()
+            }
+            CryptoError::KeyUnavailable(e) => format!("Ключ недоступен: {e}"),
+            CryptoError::KdfError(e) => format!("Ошибка получения ключа из пароля: {e}"),
+            CryptoError::InvalidKey(e) => format!("Некорректный ключ: {e}"),
+            CryptoError::WrongKey => {
+                "Неверный ключ: файл зашифрован другим ключом или паролем".to_string()
+            }
         }
     }
 }
 
+/// Проверочное значение ключа шифра, записываемое в поле `TAG_KEY_CHECK`
+pub fn key_check(key: &[u8; 32]) -> [u8; KEY_CHECK_LEN] {
+    let digest = Sha256::new()
+        .chain_update(b"crypto_files key check")
+        .chain_update(key)
+        .finalize();
+
+    let mut check = [0u8; KEY_CHECK_LEN];
+    check.copy_from_slice(&digest[..KEY_CHECK_LEN]);
+    check
+}
+
+/// Сверяет ключ с проверочным значением из заголовка до расшифровки данных.
+/// В файлах `CRYPT1` проверочного значения нет, они не проверяются.
+pub fn verify_key(key: &[u8; 32], header: &FileHeader) -> Result<(), CryptoError> {
+    match header.field(TAG_KEY_CHECK) {
+        Some(check) if check != key_check(key) => Err(CryptoError::WrongKey),
+        _ => Ok(()),
+    }
+}
+
+/// Шифрование AES-256-CTR без проверки целостности.
+///
+/// Работает только с телом файла: заголовок разбирается и записывается
+/// в `AutoCryptoService`.
 #[derive(Clone)]
 pub struct AesCtrCryptoService {
-    key: [u8; 32],
-    iv: [u8; 16],
-    cipher: Option<Aes256Ctr>,
-    is_first_chunk: bool,
+    cipher: Aes256Ctr,
 }
 
-impl From<Credentials> for AesCtrCryptoService {
-    fn from(config: Credentials) -> Self {
-        let key_slice = config.key.as_slice();
-        let mut key = [0u8; 32];
-        let copy_len = key_slice.len().min(32);
-        key[..copy_len].copy_from_slice(&key_slice[..copy_len]);
+impl AesCtrCryptoService {
+    /// Создаёт сервис со случайным IV и дописывает IV в заголовок.
+    /// Новые файлы шифруются AES-256-GCM, поэтому используется только в тестах.
+    #[cfg(test)]
+    pub fn for_encrypt(key: &[u8; 32], header: &mut FileHeader) -> Self {
+        let iv = Self::create_iv();
+        header.fields.push((TAG_NONCE, iv.to_vec()));
+        header.fields.push((TAG_KEY_CHECK, key_check(key).to_vec()));
+        Self::new(key, &iv)
+    }
+
+    /// Создаёт сервис с IV из заголовка файла. Неверный ключ отклоняется
+    /// по проверочному значению из заголовка.
+    pub fn for_decrypt(key: &[u8; 32], header: &FileHeader) -> Result<Self, CryptoError> {
+        verify_key(key, header)?;
+
+        let iv = header
+            .field(TAG_NONCE)
+            .filter(|iv| iv.len() == 16)
+            .ok_or(CryptoError::InvalidHeader("Некорректный IV".into()))?;
 
+        let mut iv_bytes = [0u8; 16];
+        iv_bytes.copy_from_slice(iv);
+        Ok(Self::new(key, &iv_bytes))
+    }
+
+    fn new(key: &[u8; 32], iv: &[u8; 16]) -> Self {
         Self {
-            key,
-            iv: [0u8; 16],
-            cipher: None,
-            is_first_chunk: true,
+            cipher: Aes256Ctr::new(key.into(), iv.into()),
         }
     }
-}
 
-impl AesCtrCryptoService {
+    #[cfg(test)]
     fn create_iv() -> [u8; 16] {
+        use rand::RngCore;
+
         let mut iv = [0u8; 16];
         rand::rng().fill_bytes(&mut iv);
         iv
     }
 
-    fn init_cipher(&mut self) {
-        if self.cipher.is_none() {
-            let c = Aes256Ctr::new(&self.key.into(), &self.iv.into());
-            self.cipher = Some(c);
-        }
-    }
-
     fn apply_cipher_to(&mut self, data: &mut [u8]) -> Result<(), CryptoError> {
-        self.init_cipher();
-        if let Some(cipher) = self.cipher.as_mut() {
-            cipher
-                .try_apply_keystream(data)
-                .map_err(|e| CryptoError::AesError(format!("{e:?}")))
-        } else {
-            Err(CryptoError::AesError("Cipher not initialized".into()))
-        }
+        self.cipher
+            .try_apply_keystream(data)
+            .map_err(|e| CryptoError::AesError(format!("{e:?}")))
     }
 
-    fn build_header_for_encrypt(&mut self) -> Vec<u8> {
-        let mut header = Vec::with_capacity(MAGIC.len() + 16);
-        header.extend_from_slice(MAGIC);
-        header.extend_from_slice(&self.iv);
-        header
+    pub fn encrypt(&mut self, mut chunk: Vec<u8>) -> Result<Vec<u8>, CryptoError> {
+        self.apply_cipher_to(&mut chunk)?;
+        Ok(chunk)
     }
-}
-
-impl CryptoService for AesCtrCryptoService {
-    type Error = CryptoError;
 
-    fn is_encrypt(&mut self, chunk: &Vec<u8>) -> Result<bool, Self::Error> {
-        Ok(chunk.starts_with(MAGIC))
+    pub fn decrypt(&mut self, mut chunk: Vec<u8>) -> Result<Vec<u8>, CryptoError> {
+        self.apply_cipher_to(&mut chunk)?;
+        Ok(chunk)
     }
 
-    fn encrypt(&mut self, mut chunk: Vec<u8>) -> Result<Vec<u8>, Self::Error> {
-        if self.is_first_chunk {
-            self.is_first_chunk = false;
-            self.iv = Self::create_iv();
-            self.cipher = None;
-            self.init_cipher();
-
-            self.apply_cipher_to(&mut chunk)?;
-
-            let mut out = self.build_header_for_encrypt();
-            out.extend_from_slice(&chunk);
-            Ok(out)
-        } else {
-            self.apply_cipher_to(&mut chunk)?;
-            Ok(chunk)
-        }
-    }
-
-    fn decrypt(&mut self, mut chunk: Vec<u8>) -> Result<Vec<u8>, Self::Error> {
-        if self.is_first_chunk {
-            self.is_first_chunk = false;
-
-            if !chunk.starts_with(MAGIC) {
-                return Err(CryptoError::InvalidHeader(
-                    "Файл не содержит MAGIC-токена".into(),
-                ));
-            }
-
-            if chunk.len() < MAGIC.len() + 16 {
-                return Err(CryptoError::InvalidHeader(
-                    "Слишком короткий заголовок".into(),
-                ));
-            }
-
-            self.iv
-                .copy_from_slice(&chunk[MAGIC.len()..MAGIC.len() + 16]);
-
-            self.cipher = None;
-            self.init_cipher();
-
-            let rest = chunk.split_off(MAGIC.len() + 16);
-            let mut data = rest;
-            if !data.is_empty() {
-                self.apply_cipher_to(&mut data)?;
-            }
-            Ok(data)
-        } else {
-            self.apply_cipher_to(&mut chunk)?;
-            Ok(chunk)
-        }
+    pub fn finish(&mut self) -> Result<Vec<u8>, CryptoError> {
+        Ok(Vec::new())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::domain::models::Credentials;
+    use crate::infrastructure::header::CipherSuite;
 
-    fn make_service() -> AesCtrCryptoService {
-        let cred = Credentials {
-            key: b"12345678901234567890123456789012".to_vec(),
-            chunk_size: 16,
-        };
+    const KEY: &[u8; 32] = b"12345678901234567890123456789012";
 
-        AesCtrCryptoService::from(cred)
+    fn make_service() -> (AesCtrCryptoService, FileHeader) {
+        let mut header = FileHeader::new(CipherSuite::Aes256Ctr, 16);
+        let service = AesCtrCryptoService::for_encrypt(KEY, &mut header);
+        (service, header)
     }
 
     #[test]
     fn test_encrypt_decrypt_chunk() {
-        let mut enc = make_service();
-        let mut dec = make_service();
+        let (mut enc, header) = make_service();
+        let mut dec = AesCtrCryptoService::for_decrypt(KEY, &header).unwrap();
 
         let chunk1 = b"Bla bla bla ".to_vec();
         let chunk2 = b"Bob Bob Bob".to_vec();
@@ -176,8 +166,8 @@ mod tests {
 
     #[test]
     fn test_different_iv() {
-        let mut s1 = make_service();
-        let mut s2 = make_service();
+        let (mut s1, _) = make_service();
+        let (mut s2, _) = make_service();
 
         let data = b"AAAAA".to_vec();
 
@@ -189,7 +179,7 @@ mod tests {
 
     #[test]
     fn test_different_encypted_data() {
-        let mut s1 = make_service();
+        let (mut s1, _) = make_service();
 
         let data = b"AAAAA".to_vec();
 
@@ -198,4 +188,33 @@ mod tests {
 
         assert_ne!(e1, e2);
     }
+
+    //Проверяем, что неверный ключ отклоняется до расшифровки данных
+    #[test]
+    fn test_wrong_key() {
+        let (_, header) = make_service();
+
+        let result = AesCtrCryptoService::for_decrypt(&[0; 32], &header);
+
+        assert!(matches!(result, Err(CryptoError::WrongKey)));
+    }
+
+    // Файлы старого формата CRYPT1 должны расшифровываться
+    #[test]
+    fn test_decrypt_legacy_header() {
+        let iv = [3u8; 16];
+        let mut body = b"legacy data".to_vec();
+        let mut cipher = Aes256Ctr::new(KEY.into(), &iv.into());
+        cipher.apply_keystream(&mut body);
+
+        let mut data = b"CRYPT1".to_vec();
+        data.extend_from_slice(&iv);
+        data.extend(body);
+
+        let (header, header_len) = FileHeader::parse(&data).unwrap().unwrap();
+        let mut dec = AesCtrCryptoService::for_decrypt(KEY, &header).unwrap();
+        let decrypted = dec.decrypt(data.split_off(header_len)).unwrap();
+
+        assert_eq!(decrypted, b"legacy data".to_vec());
+    }
 }
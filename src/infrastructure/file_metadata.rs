@@ -0,0 +1,109 @@
+use std::fs::{File, FileTimes, Metadata};
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+use crate::domain::models::PreserveOptions;
+
+/// Атрибуты исходного файла, которые переносятся на временный файл
+/// перед тем, как он заменит исходный
+pub struct FileMetadata {
+    metadata: Metadata,
+    #[cfg(unix)]
+    xattrs: Vec<(std::ffi::OsString, Vec<u8>)>,
+}
+
+/// Недостаток прав (смена владельца, системные xattr) и отсутствие
+/// поддержки xattr в файловой системе не считаются ошибкой
+fn is_denied(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::PermissionDenied | ErrorKind::Unsupported
+    )
+}
+
+/// Атрибут, который нельзя перенести, остаётся таким, каким его создал
+/// текущий пользователь
+fn ignore_denied(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if is_denied(&e) => Ok(()),
+        other => other,
+    }
+}
+
+/// Расширенные атрибуты, которые удалось прочитать. Если файловая система
+/// их не поддерживает или атрибут недоступен, он не переносится.
+#[cfg(unix)]
+fn read_xattrs(path: &Path) -> io::Result<Vec<(std::ffi::OsString, Vec<u8>)>> {
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(e) if is_denied(&e) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut xattrs = Vec::new();
+    for name in names {
+        match xattr::get(path, &name) {
+            Ok(Some(value)) => xattrs.push((name, value)),
+            Ok(None) => {}
+            Err(e) if is_denied(&e) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(xattrs)
+}
+
+impl FileMetadata {
+    pub fn capture(path: &Path, preserve: PreserveOptions) -> io::Result<Self> {
+        let metadata = std::fs::metadata(path)?;
+
+        #[cfg(unix)]
+        let xattrs = if preserve.xattrs && xattr::SUPPORTED_PLATFORM {
+            read_xattrs(path)?
+        } else {
+            Vec::new()
+        };
+        #[cfg(not(unix))]
+        let _ = preserve;
+
+        Ok(Self {
+            metadata,
+            #[cfg(unix)]
+            xattrs,
+        })
+    }
+
+    /// Применяет выбранные атрибуты к открытому файлу. Все атрибуты
+    /// меняются через дескриптор, поэтому права только на чтение,
+    /// перенесённые с исходного файла, не мешают установить время.
+    /// Время изменения устанавливается последним, после записи всех данных.
+    pub fn apply(&self, file: &File, preserve: PreserveOptions) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            use xattr::FileExt;
+
+            for (name, value) in &self.xattrs {
+                ignore_denied(file.set_xattr(name, value))?;
+            }
+            if preserve.owner {
+                ignore_denied(std::os::unix::fs::fchown(
+                    file,
+                    Some(self.metadata.uid()),
+                    Some(self.metadata.gid()),
+                ))?;
+            }
+        }
+
+        if preserve.mode {
+            file.set_permissions(self.metadata.permissions())?;
+        }
+
+        if preserve.timestamps {
+            let times = FileTimes::new()
+                .set_accessed(self.metadata.accessed()?)
+                .set_modified(self.metadata.modified()?);
+            file.set_times(times)?;
+        }
+        Ok(())
+    }
+}
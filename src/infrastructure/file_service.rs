@@ -1,14 +1,20 @@
-use std::fs::{File, remove_file, rename};
+use std::fs::{File, OpenOptions, hard_link, remove_file, rename};
 use std::io::{BufReader, BufWriter, Read, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use uuid::Uuid;
 
+use crate::domain::models::PreserveOptions;
 use crate::domain::services::{ConsoleError, FileService};
 
+use super::file_metadata::FileMetadata;
+use super::orphan_temp::{remove_origin, write_origin};
+
 #[derive(Debug)]
 pub enum FsError {
     Io(std::io::Error),
     NoTempFile,
+    NoOriginalFile,
+    OutputExists(PathBuf),
 }
 
 impl ConsoleError for FsError {
@@ -16,16 +22,186 @@ impl ConsoleError for FsError {
         match self {
             FsError::Io(e) => format!("Ошибка файловой системы: {e}"),
             FsError::NoTempFile => "Временный файл отсутствует".to_string(),
+            FsError::NoOriginalFile => "Исходный файл не открыт".to_string(),
+            FsError::OutputExists(path) => format!(
+                "Файл {} уже существует, укажите --force, чтобы перезаписать его",
+                path.display()
+            ),
         }
     }
 }
 
+/// Путь временного файла рядом с исходным: `<имя>.<uuid>.tmp`.
+/// По имени временного файла `recover` находит исходный.
+pub fn temp_path_for(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}.tmp", Uuid::new_v4()));
+    path.with_file_name(name)
+}
+
+/// Создаёт новый файл, доступный только владельцу. Существующий файл
+/// не перезаписывается.
+pub(super) fn create_private_file(path: &Path) -> std::io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+/// Создаёт новый файл, доступный только владельцу, и записывает в него
+/// секретные данные. Существующий файл не перезаписывается.
+pub fn write_secret_file(path: &Path, data: &[u8]) -> Result<(), FsError> {
+    let mut file = create_private_file(path).map_err(FsError::Io)?;
+    file.write_all(data).map_err(FsError::Io)?;
+    file.sync_all().map_err(FsError::Io)
+}
+
+/// Заменяет файл новым содержимым, доступным только владельцу: данные
+/// записываются во временный файл рядом, который затем переименовывается
+pub fn replace_secret_file(path: &Path, data: &[u8]) -> Result<(), FsError> {
+    let temp_path = temp_path_for(path);
+
+    write_secret_file(&temp_path, data)?;
+    rename(&temp_path, path).map_err(|e| {
+        let _ = remove_file(&temp_path);
+        FsError::Io(e)
+    })?;
+    sync_parent_dir(path).map_err(FsError::Io)
+}
+
+/// Сбрасывает на диск запись каталога, чтобы переименование пережило
+/// отключение питания
+pub fn sync_parent_dir(path: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        File::open(parent)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Переименовывает `from` в `to`. Без `overwrite` существующий `to` не
+/// заменяется: файл получает имя `to` жёсткой ссылкой, которая не создаётся
+/// поверх существующего файла (ошибка `AlreadyExists`), и только затем
+/// удаляется имя `from`, поэтому между проверкой и заменой нет окна.
+pub fn move_file(from: &Path, to: &Path, overwrite: bool) -> std::io::Result<()> {
+    if overwrite {
+        return rename(from, to);
+    }
+    hard_link(from, to)?;
+    // Результат уже на месте, оставшееся имя только удаляется
+    let _ = remove_file(from);
+    Ok(())
+}
+
+/// Копирует `source` с другой файловой системы во временный файл рядом
+/// с `target`, переносит на копию атрибуты через `apply`, сбрасывает её
+/// на диск и переносит на место `target` через `move_file`.
+/// `source` не удаляется.
+pub fn copy_into_place(
+    source: &Path,
+    target: &Path,
+    overwrite: bool,
+    apply: impl FnOnce(&File) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let local_path = temp_path_for(target);
+
+    let result = create_private_file(&local_path).and_then(|mut local_file| {
+        std::io::copy(&mut File::open(source)?, &mut local_file)?;
+        apply(&local_file)?;
+        local_file.sync_all()?;
+        move_file(&local_path, target, overwrite)
+    });
+    if result.is_err() {
+        let _ = remove_file(&local_path);
+    }
+    result
+}
+
+/// Находятся ли два пути на одной файловой системе, то есть можно ли
+/// переименовать файл из одного в другой
+pub fn same_filesystem(a: &Path, b: &Path) -> std::io::Result<bool> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Ok(std::fs::metadata(a)?.dev() == std::fs::metadata(b)?.dev())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (a, b);
+        Ok(true)
+    }
+}
+
+/// Находится ли каталог в оперативной памяти (tmpfs, ramfs), так что его
+/// содержимое не попадает на диск. Определяется только в Linux.
+pub fn is_volatile_dir(path: &Path) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        let (Ok(path), Ok(mounts)) = (
+            path.canonicalize(),
+            std::fs::read_to_string("/proc/self/mounts"),
+        ) else {
+            return false;
+        };
+
+        // Побеждает самая длинная точка монтирования, содержащая путь
+        mounts
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let mount_point = fields.nth(1)?.replace("\\040", " ");
+                let fs_type = fields.next()?;
+                path.starts_with(&mount_point)
+                    .then(|| (mount_point.len(), fs_type == "tmpfs" || fs_type == "ramfs"))
+            })
+            .max_by_key(|(len, _)| *len)
+            .is_some_and(|(_, volatile)| volatile)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = path;
+        false
+    }
+}
+
+/// Шаги замены исходного файла временным
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CommitStep {
+    ApplyMetadata,
+    SyncTemp,
+    Rename,
+    /// Переименование не удалось, потому что временный файл на другой
+    /// файловой системе
+    CrossDevice,
+    SyncDir,
+}
+
 pub struct LocalFileService {
     original_path: PathBuf,
     temp_path: Option<PathBuf>,
     original_reader: Option<BufReader<File>>,
     tmp_writer: Option<BufWriter<File>>,
     chunk_size: usize,
+    preserve: PreserveOptions,
+    original_metadata: Option<FileMetadata>,
+    temp_dir: Option<PathBuf>,
+    /// Файл, который заменяется результатом, если это не исходный файл
+    destination: Option<PathBuf>,
+    /// Можно ли заменить существующий файл `destination`
+    overwrite: bool,
+    /// Шаг замены, на котором тест имитирует сбой
+    #[cfg(test)]
+    fail_step: Option<CommitStep>,
 }
 
 impl LocalFileService {
@@ -36,6 +212,109 @@ impl LocalFileService {
             original_reader: None,
             tmp_writer: None,
             chunk_size,
+            preserve: PreserveOptions::default(),
+            original_metadata: None,
+            temp_dir: None,
+            destination: None,
+            overwrite: true,
+            #[cfg(test)]
+            fail_step: None,
+        }
+    }
+
+    /// Атрибуты исходного файла, которые переносятся на результат
+    pub fn with_preserve(mut self, preserve: PreserveOptions) -> Self {
+        self.preserve = preserve;
+        self
+    }
+
+    /// Каталог для временного файла вместо каталога исходного
+    pub fn with_temp_dir(mut self, temp_dir: Option<PathBuf>) -> Self {
+        self.temp_dir = temp_dir;
+        self
+    }
+
+    /// Записывать результат в другой файл вместо исходного. Без `overwrite`
+    /// существующий файл не заменяется, а `commit` завершается ошибкой
+    /// `AlreadyExists`, даже если файл появился во время обработки.
+    pub(super) fn with_destination(mut self, destination: PathBuf, overwrite: bool) -> Self {
+        self.destination = Some(destination);
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Файл, который заменяется результатом
+    fn target_path(&self) -> &Path {
+        self.destination.as_deref().unwrap_or(&self.original_path)
+    }
+
+    fn fail_at(&self, step: CommitStep) -> std::io::Result<()> {
+        #[cfg(test)]
+        if self.fail_step == Some(step) {
+            let kind = match step {
+                CommitStep::CrossDevice => std::io::ErrorKind::CrossesDevices,
+                _ => std::io::ErrorKind::Other,
+            };
+            return Err(std::io::Error::new(kind, format!("сбой на шаге {step:?}")));
+        }
+        #[cfg(not(test))]
+        let _ = step;
+        Ok(())
+    }
+
+    /// Переносит атрибуты, сбрасывает временный файл на диск и переименовывает
+    /// его поверх исходного. До переименования исходный файл не меняется.
+    fn replace_original(&mut self, temp_path: &Path) -> std::io::Result<()> {
+        let writer = self
+            .tmp_writer
+            .take()
+            .ok_or(std::io::Error::other("Writer отсутствует"))?;
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+
+        if let Some(metadata) = &self.original_metadata {
+            self.fail_at(CommitStep::ApplyMetadata)?;
+            metadata.apply(&file, self.preserve)?;
+        }
+
+        self.fail_at(CommitStep::SyncTemp)?;
+        file.sync_all()?;
+        drop(file);
+
+        self.fail_at(CommitStep::Rename)?;
+        match self
+            .fail_at(CommitStep::CrossDevice)
+            .and_then(|_| move_file(temp_path, self.target_path(), self.overwrite))
+        {
+            Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+                self.copy_over_original(temp_path)
+            }
+            result => result,
+        }
+    }
+
+    /// Временный файл с другой файловой системы копируется во второй
+    /// временный файл рядом с исходным, который сбрасывается на диск
+    /// и переименовывается поверх исходного
+    fn copy_over_original(&self, temp_path: &Path) -> std::io::Result<()> {
+        copy_into_place(
+            temp_path,
+            self.target_path(),
+            self.overwrite,
+            |local_file| match &self.original_metadata {
+                Some(metadata) => metadata.apply(local_file, self.preserve),
+                None => Ok(()),
+            },
+        )?;
+
+        // Исходный файл уже заменён, оставшийся временный только удаляется
+        let _ = remove_file(temp_path);
+        Ok(())
+    }
+
+    /// Удаляет запись о пути исходного файла для временного файла в `temp_dir`
+    fn forget_origin(&self, temp_path: &Path) {
+        if self.temp_dir.is_some() {
+            let _ = remove_origin(temp_path);
         }
     }
 }
@@ -45,18 +324,34 @@ impl FileService for LocalFileService {
 
     fn init_original(&mut self) -> Result<&mut Self, Self::Error> {
         let original_file = File::open(&self.original_path).map_err(FsError::Io)?;
+        self.original_metadata =
+            Some(FileMetadata::capture(&self.original_path, self.preserve).map_err(FsError::Io)?);
         self.original_reader = Some(BufReader::new(original_file));
         Ok(self)
     }
 
     fn make_temp(&mut self) -> Result<&mut Self, Self::Error> {
-        let uuid = Uuid::new_v4().to_string();
-
-        let temp_path = self.original_path.with_extension(format!("{uuid}.tmp"));
+        let temp_path = match &self.temp_dir {
+            Some(dir) => {
+                let temp_path =
+                    temp_path_for(&dir.join(self.target_path().file_name().unwrap_or_default()));
+                // Исходный файл в другом каталоге, `recover` узнаёт его путь
+                // из записи рядом с временным файлом
+                write_origin(&temp_path, self.target_path()).map_err(FsError::Io)?;
+                temp_path
+            }
+            None => temp_path_for(self.target_path()),
+        };
 
         //rename(&self.original_path, &temp_path).map_err(FsError::Io)?;
 
-        let new_file = File::create(&temp_path).map_err(FsError::Io)?;
+        // Временный файл доступен только владельцу, пока в нём могут
+        // оказаться расшифрованные данные; права исходного файла
+        // переносятся перед заменой
+        let new_file = create_private_file(&temp_path).map_err(|e| {
+            self.forget_origin(&temp_path);
+            FsError::Io(e)
+        })?;
 
         self.tmp_writer = Some(BufWriter::new(new_file));
 
@@ -65,32 +360,58 @@ impl FileService for LocalFileService {
         Ok(self)
     }
 
-    fn rename_temp_as_original(&mut self) -> Result<&mut Self, Self::Error> {
-        let temp_path = self.temp_path.take().ok_or(FsError::NoTempFile)?;
-        rename(&temp_path, &self.original_path).map_err(FsError::Io)?;
-        Ok(self)
-    }
+    fn commit(&mut self) -> Result<&mut Self, Self::Error> {
+        let temp_path = self.temp_path.clone().ok_or(FsError::NoTempFile)?;
 
-    fn delete_original(&mut self) -> Result<&mut Self, Self::Error> {
-        remove_file(&self.original_path).map_err(FsError::Io)?;
+        let result = self.replace_original(&temp_path);
+        self.forget_origin(&temp_path);
+        if let Err(e) = result {
+            self.tmp_writer = None;
+            self.temp_path = None;
+            let _ = remove_file(&temp_path);
+            return Err(FsError::Io(e));
+        }
 
-        self.tmp_writer = None;
+        self.temp_path = None;
+        self.original_reader = None;
 
+        self.fail_at(CommitStep::SyncDir)
+            .and_then(|_| sync_parent_dir(self.target_path()))
+            .map_err(FsError::Io)?;
         Ok(self)
     }
 
-    fn read_chunk_original(&mut self) -> Vec<u8> {
-        let Some(reader) = self.original_reader.as_mut() else {
-            return Vec::new();
-        };
+    fn read_chunk_original(&mut self) -> Result<Option<Vec<u8>>, Self::Error> {
+        let reader = self
+            .original_reader
+            .as_mut()
+            .ok_or(FsError::NoOriginalFile)?;
 
         let mut buf = vec![0u8; self.chunk_size];
-        match reader.read(&mut buf) {
-            Ok(0) | Err(_) => Vec::new(),
-            Ok(n) => buf[..n].to_vec(),
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => return Ok(None),
+                Ok(n) => return Ok(Some(buf[..n].to_vec())),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(FsError::Io(e)),
+            }
         }
     }
 
+    fn read_exact_original(&mut self, len: usize) -> Result<Vec<u8>, Self::Error> {
+        let reader = self
+            .original_reader
+            .as_mut()
+            .ok_or(FsError::NoOriginalFile)?;
+
+        let mut buf = Vec::with_capacity(len);
+        reader
+            .take(len as u64)
+            .read_to_end(&mut buf)
+            .map_err(FsError::Io)?;
+        Ok(buf)
+    }
+
     fn write_chunk(&mut self, chunk: Vec<u8>) -> Result<(), Self::Error> {
         let writer = self
             .tmp_writer
@@ -106,6 +427,7 @@ impl FileService for LocalFileService {
     fn revert(&mut self) -> Result<&mut Self, Self::Error> {
         let temp = self.temp_path.take().ok_or(FsError::NoTempFile)?;
 
+        self.forget_origin(&temp);
         remove_file(&temp).map_err(FsError::Io)?;
 
         self.tmp_writer = None;
@@ -113,3 +435,188 @@ impl FileService for LocalFileService {
         Ok(self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    fn make_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("crypto_files_{name}_{}", Uuid::new_v4()));
+        fs::write(&path, b"secret").unwrap();
+        path
+    }
+
+    fn replace(path: &Path, preserve: PreserveOptions) {
+        let mut service = LocalFileService::new(16, path).with_preserve(preserve);
+        service.init_original().unwrap();
+        service.make_temp().unwrap();
+        let chunk = service.read_chunk_original().unwrap().unwrap();
+        service.write_chunk(chunk).unwrap();
+        service.commit().unwrap();
+    }
+
+    /// Заменяет содержимое файла, имитируя сбой на шаге `step`
+    fn replace_with_failure(path: &Path, step: CommitStep) -> Result<(), FsError> {
+        let mut service = LocalFileService::new(16, path);
+        service.fail_step = Some(step);
+        service.init_original()?;
+        service.make_temp()?;
+        service.write_chunk(b"new data".to_vec())?;
+        service.commit().map(|_| ())
+    }
+
+    fn temp_files(path: &Path) -> Vec<PathBuf> {
+        let prefix = path.file_name().unwrap().to_string_lossy().into_owned();
+        fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|p| {
+                let name = p.file_name().unwrap().to_string_lossy();
+                name.starts_with(&prefix) && name.ends_with(".tmp")
+            })
+            .collect()
+    }
+
+    //Проверяем, что при сбое до переименования исходный файл не меняется,
+    //а временный файл удаляется
+    #[test]
+    fn test_failure_before_rename() {
+        for step in [
+            CommitStep::ApplyMetadata,
+            CommitStep::SyncTemp,
+            CommitStep::Rename,
+        ] {
+            let path = make_file("commit");
+
+            let result = replace_with_failure(&path, step);
+
+            assert!(result.is_err(), "{step:?}");
+            assert_eq!(fs::read(&path).unwrap(), b"secret", "{step:?}");
+            assert!(temp_files(&path).is_empty(), "{step:?}");
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    //Проверяем, что при сбое после переименования файл уже заменён целиком
+    #[test]
+    fn test_failure_after_rename() {
+        let path = make_file("commit");
+
+        let result = replace_with_failure(&path, CommitStep::SyncDir);
+
+        assert!(result.is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"new data");
+        assert!(temp_files(&path).is_empty());
+        fs::remove_file(path).unwrap();
+    }
+
+    //Проверяем, что ошибка чтения не выдаётся за конец файла
+    #[test]
+    fn test_read_error() {
+        let dir = std::env::temp_dir().join(format!("crypto_files_read_{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+
+        let mut service = LocalFileService::new(16, &dir);
+        assert!(matches!(
+            service.read_chunk_original(),
+            Err(FsError::NoOriginalFile)
+        ));
+        service.init_original().unwrap();
+        assert!(matches!(service.read_chunk_original(), Err(FsError::Io(_))));
+        fs::remove_dir(dir).unwrap();
+    }
+
+    //Проверяем, что временный файл создаётся в заданном каталоге, а с другой
+    //файловой системы исходный заменяется через копию рядом с ним
+    #[test]
+    fn test_temp_dir_cross_device() {
+        let path = make_file("temp_dir");
+        let temp_dir =
+            std::env::temp_dir().join(format!("crypto_files_temp_dir_{}", Uuid::new_v4()));
+        fs::create_dir(&temp_dir).unwrap();
+
+        let mut service = LocalFileService::new(16, &path).with_temp_dir(Some(temp_dir.clone()));
+        service.fail_step = Some(CommitStep::CrossDevice);
+        service.init_original().unwrap();
+        service.make_temp().unwrap();
+        // Временный файл и запись с путём исходного
+        assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 2);
+        assert!(temp_files(&path).is_empty());
+
+        service.write_chunk(b"new data".to_vec()).unwrap();
+        service.commit().unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"new data");
+        assert!(temp_files(&path).is_empty());
+        assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+        fs::remove_file(path).unwrap();
+        fs::remove_dir(temp_dir).unwrap();
+    }
+
+    //Проверяем, что права и время изменения переживают замену файла
+    #[cfg(unix)]
+    #[test]
+    fn test_preserve_metadata() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = make_file("preserve");
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        replace(&path, PreserveOptions::default());
+
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o640);
+        assert_eq!(metadata.modified().unwrap(), modified);
+        fs::remove_file(path).unwrap();
+    }
+
+    //Проверяем, что исходный файл только для чтения заменяется
+    //с сохранением прав и времени изменения
+    #[cfg(unix)]
+    #[test]
+    fn test_preserve_read_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = make_file("preserve_read_only");
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o400)).unwrap();
+
+        replace(&path, PreserveOptions::default());
+
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o400);
+        assert_eq!(metadata.modified().unwrap(), modified);
+        fs::remove_file(path).unwrap();
+    }
+
+    //Проверяем, что без сохранения прав результат доступен только владельцу
+    #[cfg(unix)]
+    #[test]
+    fn test_preserve_none() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = make_file("preserve_none");
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        replace(&path, PreserveOptions::none());
+
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        fs::remove_file(path).unwrap();
+    }
+}
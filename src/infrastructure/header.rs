@@ -0,0 +1,412 @@
+use crate::domain::models::HeaderProbe;
+
+use super::crypto_service::CryptoError;
+
+/// MAGIC-токен устаревшего формата: `CRYPT1` и 16 байт IV
+pub const LEGACY_MAGIC: &[u8] = b"CRYPT1";
+pub const MAGIC: &[u8] = b"CRYPT2";
+pub const VERSION: u8 = 1;
+
+const LEGACY_IV_LEN: usize = 16;
+const LEGACY_HEADER_LEN: usize = LEGACY_MAGIC.len() + LEGACY_IV_LEN;
+
+/// magic + version + header_len
+const PREFIX_LEN: usize = MAGIC.len() + 1 + 4;
+/// suite + chunk_size
+const FIXED_LEN: usize = 1 + 4;
+const CHECKSUM_LEN: usize = 4;
+const MAX_HEADER_LEN: usize = 1024 * 1024;
+
+/// IV или префикс nonce шифра
+pub const TAG_NONCE: u8 = 0x01;
+/// Параметры Argon2id и соль, если ключ получен из пароля
+pub const TAG_KDF: u8 = 0x02;
+/// Отпечаток ключа из связки, которым зашифрован файл
+pub const TAG_KEY_ID: u8 = 0x03;
+/// Ключ данных файла, завёрнутый мастер-ключом по AES-KW
+pub const TAG_WRAPPED_KEY: u8 = 0x04;
+/// Ключ данных файла, завёрнутый для получателя X25519; поле повторяется
+/// для каждого получателя
+pub const TAG_RECIPIENT: u8 = 0x05;
+/// Проверочное значение ключа данных
+pub const TAG_KEY_CHECK: u8 = 0x06;
+
+/// Поля, которые `recipients` и `rekey` переписывают без перешифрования тела.
+/// Их подмена обнаруживается при разворачивании ключа данных.
+const REWRITABLE_TAGS: [u8; 4] = [TAG_KDF, TAG_KEY_ID, TAG_WRAPPED_KEY, TAG_RECIPIENT];
+
+/// Набор алгоритмов, которым зашифрован файл
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CipherSuite {
+    Aes256Ctr = 1,
+    Aes256GcmStream = 2,
+}
+
+impl CipherSuite {
+    pub fn from_id(id: u8) -> Result<Self, CryptoError> {
+        match id {
+            1 => Ok(Self::Aes256Ctr),
+            2 => Ok(Self::Aes256GcmStream),
+            other => Err(CryptoError::InvalidHeader(format!(
+                "Неизвестный набор алгоритмов {other}"
+            ))),
+        }
+    }
+
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+
+    /// Набор алгоритмов по имени из командной строки
+    pub fn from_name(name: &str) -> Result<Self, CryptoError> {
+        match name {
+            "aes-256-ctr" => Ok(Self::Aes256Ctr),
+            "aes-256-gcm" => Ok(Self::Aes256GcmStream),
+            other => Err(CryptoError::InvalidKey(format!(
+                "неизвестный набор алгоритмов {other}"
+            ))),
+        }
+    }
+
+    /// Длина ключа в байтах
+    pub fn key_len(self) -> usize {
+        match self {
+            Self::Aes256Ctr | Self::Aes256GcmStream => 32,
+        }
+    }
+}
+
+/// Заголовок зашифрованного файла.
+///
+/// Формат `CRYPT2`: magic, версия, полная длина заголовка, набор алгоритмов,
+/// размер блока, необязательные поля TLV и CRC32 всех предыдущих байт.
+/// Файлы `CRYPT1` разбираются в тот же тип с единственным полем `TAG_NONCE`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileHeader {
+    pub version: u8,
+    pub suite: CipherSuite,
+    pub chunk_size: u32,
+    pub fields: Vec<(u8, Vec<u8>)>,
+}
+
+impl FileHeader {
+    pub fn new(suite: CipherSuite, chunk_size: u32) -> Self {
+        Self {
+            version: VERSION,
+            suite,
+            chunk_size,
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn field(&self, tag: u8) -> Option<&[u8]> {
+        self.fields
+            .iter()
+            .find(|(t, _)| *t == tag)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    /// Значения всех полей с тегом `tag`
+    pub fn fields_with(&self, tag: u8) -> impl Iterator<Item = &[u8]> {
+        self.fields
+            .iter()
+            .filter(move |(t, _)| *t == tag)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    /// Удаляет все поля с тегом `tag`
+    pub fn remove(&mut self, tag: u8) {
+        self.fields.retain(|(t, _)| *t != tag);
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, CryptoError> {
+        let mut body = Vec::new();
+        body.push(self.suite.id());
+        body.extend_from_slice(&self.chunk_size.to_be_bytes());
+
+        for (tag, value) in &self.fields {
+            let len = u16::try_from(value.len())
+                .map_err(|_| CryptoError::InvalidHeader(format!("Слишком длинное поле {tag}")))?;
+            body.push(*tag);
+            body.extend_from_slice(&len.to_be_bytes());
+            body.extend_from_slice(value);
+        }
+
+        let header_len = PREFIX_LEN + body.len() + CHECKSUM_LEN;
+        if header_len > MAX_HEADER_LEN {
+            return Err(CryptoError::InvalidHeader(
+                "Слишком длинный заголовок".into(),
+            ));
+        }
+
+        let mut out = Vec::with_capacity(header_len);
+        out.extend_from_slice(MAGIC);
+        out.push(self.version);
+        out.extend_from_slice(&(header_len as u32).to_be_bytes());
+        out.extend(body);
+        out.extend_from_slice(&crc32fast::hash(&out).to_be_bytes());
+        Ok(out)
+    }
+
+    /// Заголовок без переписываемых полей, который аутентифицируется вместе
+    /// с каждым сегментом. CRC32 защищает только от случайных повреждений.
+    pub fn associated_data(&self) -> Result<Vec<u8>, CryptoError> {
+        let mut fixed = self.clone();
+        fixed
+            .fields
+            .retain(|(tag, _)| !REWRITABLE_TAGS.contains(tag));
+        fixed.encode()
+    }
+
+    /// Разбирает заголовок в начале `data`.
+    ///
+    /// Возвращает `Ok(None)`, если данных пока недостаточно, иначе заголовок
+    /// и его длину в байтах.
+    pub fn parse(data: &[u8]) -> Result<Option<(Self, usize)>, CryptoError> {
+        let magic_len = MAGIC.len().min(data.len());
+        if data[..magic_len] == LEGACY_MAGIC[..magic_len] {
+            return Ok(Self::parse_legacy(data));
+        }
+        if data[..magic_len] != MAGIC[..magic_len] {
+            return Err(CryptoError::InvalidHeader(
+                "Файл не содержит MAGIC-токена".into(),
+            ));
+        }
+        if data.len() < PREFIX_LEN {
+            return Ok(None);
+        }
+
+        let version = data[MAGIC.len()];
+        if version != VERSION {
+            return Err(CryptoError::InvalidHeader(format!(
+                "Неподдерживаемая версия заголовка {version}"
+            )));
+        }
+
+        let header_len = read_u32(&data[MAGIC.len() + 1..]) as usize;
+        if !(PREFIX_LEN + FIXED_LEN + CHECKSUM_LEN..=MAX_HEADER_LEN).contains(&header_len) {
+            return Err(CryptoError::InvalidHeader(format!(
+                "Некорректная длина заголовка {header_len}"
+            )));
+        }
+        if data.len() < header_len {
+            return Ok(None);
+        }
+
+        let checksum_at = header_len - CHECKSUM_LEN;
+        if crc32fast::hash(&data[..checksum_at]) != read_u32(&data[checksum_at..]) {
+            return Err(CryptoError::InvalidHeader(
+                "Контрольная сумма заголовка не совпадает".into(),
+            ));
+        }
+
+        let body = &data[PREFIX_LEN..checksum_at];
+        let suite = CipherSuite::from_id(body[0])?;
+        let chunk_size = read_u32(&body[1..]);
+
+        let mut fields = Vec::new();
+        let mut rest = &body[FIXED_LEN..];
+        while !rest.is_empty() {
+            if rest.len() < 3 {
+                return Err(CryptoError::InvalidHeader("Обрезанное поле TLV".into()));
+            }
+            let tag = rest[0];
+            let len = u16::from_be_bytes([rest[1], rest[2]]) as usize;
+            let value = rest[3..]
+                .get(..len)
+                .ok_or(CryptoError::InvalidHeader("Обрезанное поле TLV".into()))?;
+            fields.push((tag, value.to_vec()));
+            rest = &rest[3 + len..];
+        }
+
+        let header = Self {
+            version,
+            suite,
+            chunk_size,
+            fields,
+        };
+        Ok(Some((header, header_len)))
+    }
+
+    fn parse_legacy(data: &[u8]) -> Option<(Self, usize)> {
+        if data.len() < LEGACY_HEADER_LEN {
+            return None;
+        }
+
+        let header = Self {
+            version: 0,
+            suite: CipherSuite::Aes256Ctr,
+            chunk_size: 0,
+            fields: vec![(
+                TAG_NONCE,
+                data[LEGACY_MAGIC.len()..LEGACY_HEADER_LEN].to_vec(),
+            )],
+        };
+        Some((header, LEGACY_HEADER_LEN))
+    }
+
+    /// Разбирает заголовок, который занимает `data` целиком
+    pub fn decode(data: &[u8]) -> Result<Self, CryptoError> {
+        match Self::parse(data)? {
+            Some((header, len)) if len == data.len() => Ok(header),
+            _ => Err(CryptoError::InvalidHeader(
+                "Заголовок прочитан не полностью".into(),
+            )),
+        }
+    }
+
+    /// Проверяет, начинается ли файл с заголовка, по прочитанному началу `head`.
+    ///
+    /// Сначала нужны magic, затем префикс `CRYPT2` с длиной заголовка, затем
+    /// заголовок целиком, поэтому начало файла читается точными порциями
+    /// независимо от размера части. Текст, который только начинается с magic,
+    /// заголовком не считается; повреждённый заголовок `CRYPT2` — ошибка.
+    /// Заголовок `CRYPT1` — любые 16 байт после magic, поэтому он
+    /// возвращается как `HeaderProbe::Legacy`, а не как доказательство
+    /// шифрования.
+    pub fn probe(head: &[u8]) -> Result<HeaderProbe, CryptoError> {
+        let magic_len = MAGIC.len().min(head.len());
+        let is_legacy = head[..magic_len] == LEGACY_MAGIC[..magic_len];
+        if !is_legacy && head[..magic_len] != MAGIC[..magic_len] {
+            return Ok(HeaderProbe::Plain);
+        }
+
+        let header_len = if head.len() < MAGIC.len() {
+            MAGIC.len()
+        } else if is_legacy {
+            LEGACY_HEADER_LEN
+        } else if head.len() < PREFIX_LEN {
+            PREFIX_LEN
+        } else if head[MAGIC.len()] != VERSION {
+            return Ok(HeaderProbe::Plain);
+        } else {
+            // Проверяет допустимость длины до чтения остального заголовка
+            Self::parse(&head[..PREFIX_LEN])?;
+            read_u32(&head[MAGIC.len() + 1..]) as usize
+        };
+
+        if head.len() < header_len {
+            return Ok(HeaderProbe::Incomplete(header_len - head.len()));
+        }
+        Self::parse(head)?;
+        Ok(match is_legacy {
+            true => HeaderProbe::Legacy,
+            false => HeaderProbe::Complete,
+        })
+    }
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&data[..4]);
+    u32::from_be_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_header() -> FileHeader {
+        let mut header = FileHeader::new(CipherSuite::Aes256GcmStream, 4096);
+        header.fields.push((TAG_NONCE, vec![7; 7]));
+        header
+    }
+
+    #[test]
+    fn test_encode_parse() {
+        let header = make_header();
+        let mut data = header.encode().unwrap();
+        let header_len = data.len();
+        data.extend_from_slice(b"body");
+
+        let (parsed, len) = FileHeader::parse(&data).unwrap().unwrap();
+
+        assert_eq!(parsed, header);
+        assert_eq!(len, header_len);
+    }
+
+    #[test]
+    fn test_incomplete_header() {
+        let data = make_header().encode().unwrap();
+
+        for len in 1..data.len() {
+            assert!(FileHeader::parse(&data[..len]).unwrap().is_none());
+        }
+    }
+
+    //Проверяем, что заголовок читается точными порциями и разбирается целиком
+    #[test]
+    fn test_probe() {
+        let mut data = make_header().encode().unwrap();
+        let header_len = data.len();
+        data.extend_from_slice(b"body");
+
+        let mut head = Vec::new();
+        let mut reads = Vec::new();
+        while let HeaderProbe::Incomplete(len) = FileHeader::probe(&head).unwrap() {
+            reads.push(len);
+            head.extend_from_slice(&data[head.len()..head.len() + len]);
+        }
+
+        assert_eq!(
+            reads,
+            vec![
+                MAGIC.len(),
+                PREFIX_LEN - MAGIC.len(),
+                header_len - PREFIX_LEN
+            ]
+        );
+        assert_eq!(FileHeader::probe(&head).unwrap(), HeaderProbe::Complete);
+        assert_eq!(FileHeader::decode(&head).unwrap(), make_header());
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let mut data = make_header().encode().unwrap();
+        data[PREFIX_LEN + 2] ^= 1;
+
+        assert!(FileHeader::parse(&data).is_err());
+        assert!(FileHeader::probe(&data).is_err());
+    }
+
+    #[test]
+    fn test_legacy_header() {
+        let mut data = LEGACY_MAGIC.to_vec();
+        data.extend_from_slice(&[1; 16]);
+
+        let (parsed, len) = FileHeader::parse(&data).unwrap().unwrap();
+
+        assert_eq!(parsed.version, 0);
+        assert_eq!(parsed.suite, CipherSuite::Aes256Ctr);
+        assert_eq!(parsed.field(TAG_NONCE), Some(&[1u8; 16][..]));
+        assert_eq!(len, data.len());
+        assert_eq!(FileHeader::probe(&data).unwrap(), HeaderProbe::Legacy);
+    }
+
+    #[test]
+    fn test_plaintext_with_magic() {
+        let data = b"CRYPT2 is the name of this document".to_vec();
+
+        assert_eq!(
+            FileHeader::probe(&data[..PREFIX_LEN]).unwrap(),
+            HeaderProbe::Plain
+        );
+        assert_eq!(
+            FileHeader::probe(b"CRY").unwrap(),
+            HeaderProbe::Incomplete(3)
+        );
+        assert_eq!(FileHeader::probe(b"plain").unwrap(), HeaderProbe::Plain);
+    }
+
+    //Проверяем, что текст, который начинается с CRYPT1, не считается
+    //зашифрованным, а распознаётся как неоднозначный заголовок
+    #[test]
+    fn test_plaintext_with_legacy_magic() {
+        let data = b"CRYPT1 is the name of this document";
+
+        assert_eq!(
+            FileHeader::probe(&data[..LEGACY_HEADER_LEN]).unwrap(),
+            HeaderProbe::Legacy
+        );
+    }
+}
@@ -0,0 +1,175 @@
+use crate::domain::models::HeaderProbe;
+use crate::domain::services::HeaderService;
+
+use super::crypto_service::CryptoError;
+use super::header::{FileHeader, TAG_WRAPPED_KEY};
+use super::key_resolver::KeyResolver;
+
+/// Изменение заголовка
+pub enum HeaderEdit {
+    /// Завернуть ключ данных мастер-ключом из `with_target`
+    Rewrap,
+    AddRecipients(Vec<String>),
+    RemoveRecipients(Vec<String>),
+}
+
+/// Изменяет способы получения ключа данных файла.
+///
+/// Меняется только заголовок: тело файла копируется как есть,
+/// поэтому смена мастер-ключа или получателей не требует перешифрования данных.
+pub struct HeaderRewriter {
+    keys: KeyResolver,
+    /// Ключи для нового мастер-ключа при `HeaderEdit::Rewrap`
+    target: Option<KeyResolver>,
+    edit: HeaderEdit,
+}
+
+impl From<KeyResolver> for HeaderRewriter {
+    fn from(keys: KeyResolver) -> Self {
+        Self {
+            keys,
+            target: None,
+            edit: HeaderEdit::Rewrap,
+        }
+    }
+}
+
+impl HeaderRewriter {
+    pub fn with_edit(mut self, edit: HeaderEdit) -> Self {
+        self.edit = edit;
+        self
+    }
+
+    /// Ключи, мастер-ключом из которых заворачивается ключ данных.
+    /// По умолчанию это те же ключи, которыми он разворачивается.
+    pub fn with_target(mut self, target: KeyResolver) -> Self {
+        self.target = Some(target);
+        self
+    }
+
+    fn edit(&self, header: &FileHeader) -> Result<FileHeader, CryptoError> {
+        match &self.edit {
+            HeaderEdit::Rewrap => self
+                .keys
+                .rewrap(header, self.target.as_ref().unwrap_or(&self.keys)),
+            HeaderEdit::AddRecipients(recipients) => self.keys.add_recipients(header, recipients),
+            HeaderEdit::RemoveRecipients(recipients) => {
+                self.keys.remove_recipients(header, recipients)
+            }
+        }
+    }
+}
+
+impl HeaderService for HeaderRewriter {
+    type Error = CryptoError;
+
+    fn probe_header(&self, head: &[u8]) -> Result<HeaderProbe, Self::Error> {
+        FileHeader::probe(head)
+    }
+
+    fn can_rewrite(&mut self, header: &[u8]) -> Result<bool, Self::Error> {
+        Ok(match FileHeader::decode(header) {
+            Ok(header) => header.field(TAG_WRAPPED_KEY).is_some(),
+            Err(_) => false,
+        })
+    }
+
+    fn rewrite(&mut self, header: &[u8]) -> Result<Vec<u8>, Self::Error> {
+        self.edit(&FileHeader::decode(header)?)?.encode()
+    }
+
+    fn describe(&mut self, header: &[u8]) -> Result<Vec<String>, Self::Error> {
+        Ok(self.keys.describe(&FileHeader::decode(header)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::models::{Credentials, KdfSettings};
+    use crate::domain::services::CryptoService;
+    use crate::infrastructure::auto_crypto_service::AutoCryptoService;
+    use crate::infrastructure::header::TAG_KDF;
+    use std::collections::BTreeMap;
+
+    //Проверяем, что после смены мастер-ключа тело файла не меняется,
+    //а файл расшифровывается только новым ключом
+    #[test]
+    fn test_rewrap_keeps_body() {
+        let old_key = ("old".to_string(), vec![1; 32]);
+        let new_key = ("new".to_string(), vec![2; 32]);
+        let old = Credentials {
+            keys: BTreeMap::from([old_key.clone()]),
+            chunk_size: 8,
+            ..Default::default()
+        };
+
+        let mut enc = AutoCryptoService::from(old.clone());
+        let mut encrypted = enc.encrypt(b"Bla bla bla Bob Bob Bob".to_vec()).unwrap();
+        encrypted.extend(enc.finish().unwrap());
+        let (_, old_header_len) = FileHeader::parse(&encrypted).unwrap().unwrap();
+        let (old_header, body) = encrypted.split_at(old_header_len);
+
+        let mut rewriter = HeaderRewriter::from(KeyResolver::from(Credentials {
+            keys: BTreeMap::from([old_key, new_key.clone()]),
+            default_key: Some("new".into()),
+            ..Default::default()
+        }));
+        assert!(rewriter.can_rewrite(old_header).unwrap());
+        let new_header = rewriter.rewrite(old_header).unwrap();
+
+        let mut dec = AutoCryptoService::from(Credentials {
+            keys: BTreeMap::from([new_key]),
+            ..Default::default()
+        });
+        dec.init_decrypt(&new_header).unwrap();
+        let mut decrypted = dec.decrypt(body.to_vec()).unwrap();
+        decrypted.extend(dec.finish().unwrap());
+        assert_eq!(decrypted, b"Bla bla bla Bob Bob Bob".to_vec());
+
+        let mut old = AutoCryptoService::from(old);
+        assert!(old.init_decrypt(&new_header).is_err());
+    }
+
+    //Проверяем, что файл, зашифрованный паролем, переводится на ключ из связки,
+    //хотя в настройках для расшифровки пароль по-прежнему задан
+    #[test]
+    fn test_rewrap_passphrase_to_key() {
+        let source = Credentials {
+            passphrase: Some("old secret".into()),
+            chunk_size: 8,
+            kdf: KdfSettings {
+                memory_kib: 8,
+                iterations: 1,
+                parallelism: 1,
+            },
+            ..Default::default()
+        };
+        let target = Credentials {
+            keys: BTreeMap::from([("new".to_string(), vec![2; 32])]),
+            ..Default::default()
+        };
+
+        let mut enc = AutoCryptoService::from(source.clone());
+        let mut encrypted = enc.encrypt(b"Bla bla bla".to_vec()).unwrap();
+        encrypted.extend(enc.finish().unwrap());
+        let (_, old_header_len) = FileHeader::parse(&encrypted).unwrap().unwrap();
+        let (old_header, body) = encrypted.split_at(old_header_len);
+
+        let mut rewriter = HeaderRewriter::from(KeyResolver::from(source))
+            .with_target(KeyResolver::from(target.clone()));
+        let new_header = rewriter.rewrite(old_header).unwrap();
+        assert!(
+            FileHeader::decode(&new_header)
+                .unwrap()
+                .field(TAG_KDF)
+                .is_none()
+        );
+
+        let mut dec = AutoCryptoService::from(target);
+        dec.init_decrypt(&new_header).unwrap();
+        let mut decrypted = dec.decrypt(body.to_vec()).unwrap();
+        decrypted.extend(dec.finish().unwrap());
+        assert_eq!(decrypted, b"Bla bla bla".to_vec());
+    }
+}
@@ -0,0 +1,126 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use rand::RngCore;
+
+use crate::domain::models::KdfSettings;
+
+use super::crypto_service::CryptoError;
+
+const ALGORITHM_ARGON2ID: u8 = 1;
+const SALT_LEN: usize = 16;
+const ENCODED_LEN: usize = 1 + 4 * 3 + SALT_LEN;
+
+/// Ограничения на параметры из заголовка, чтобы чужой файл
+/// не мог заставить выделить неограниченный объём памяти
+const MAX_MEMORY_KIB: u32 = 4 * 1024 * 1024;
+const MAX_ITERATIONS: u32 = 64;
+const MAX_PARALLELISM: u32 = 64;
+
+/// Параметры Argon2id, сохраняемые в заголовке в поле `TAG_KDF`
+#[derive(Debug, Clone, PartialEq)]
+pub struct KdfParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+    pub salt: [u8; SALT_LEN],
+}
+
+impl KdfParams {
+    /// Параметры для нового файла со случайной солью
+    pub fn generate(settings: &KdfSettings) -> Self {
+        let mut salt = [0u8; SALT_LEN];
+        rand::rng().fill_bytes(&mut salt);
+
+        Self {
+            memory_kib: settings.memory_kib,
+            iterations: settings.iterations,
+            parallelism: settings.parallelism,
+            salt,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(ENCODED_LEN);
+        out.push(ALGORITHM_ARGON2ID);
+        out.extend_from_slice(&self.memory_kib.to_be_bytes());
+        out.extend_from_slice(&self.iterations.to_be_bytes());
+        out.extend_from_slice(&self.parallelism.to_be_bytes());
+        out.extend_from_slice(&self.salt);
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, CryptoError> {
+        if data.len() != ENCODED_LEN || data[0] != ALGORITHM_ARGON2ID {
+            return Err(CryptoError::InvalidHeader(
+                "Некорректные параметры KDF".into(),
+            ));
+        }
+
+        let read_u32 =
+            |at: usize| u32::from_be_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]);
+        let mut salt = [0u8; SALT_LEN];
+        salt.copy_from_slice(&data[13..]);
+
+        let params = Self {
+            memory_kib: read_u32(1),
+            iterations: read_u32(5),
+            parallelism: read_u32(9),
+            salt,
+        };
+
+        if params.memory_kib > MAX_MEMORY_KIB
+            || params.iterations > MAX_ITERATIONS
+            || params.parallelism > MAX_PARALLELISM
+        {
+            return Err(CryptoError::InvalidHeader(
+                "Слишком большие параметры KDF".into(),
+            ));
+        }
+        Ok(params)
+    }
+
+    /// Получает 32-байтовый ключ из пароля
+    pub fn derive_key(&self, passphrase: &str) -> Result<[u8; 32], CryptoError> {
+        let params = Params::new(self.memory_kib, self.iterations, self.parallelism, Some(32))
+            .map_err(|e| CryptoError::KdfError(e.to_string()))?;
+
+        let mut key = [0u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &self.salt, &mut key)
+            .map_err(|e| CryptoError::KdfError(e.to_string()))?;
+        Ok(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_params() -> KdfParams {
+        KdfParams::generate(&KdfSettings {
+            memory_kib: 64,
+            iterations: 1,
+            parallelism: 1,
+        })
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let params = make_params();
+
+        let decoded = KdfParams::decode(&params.encode()).unwrap();
+
+        assert_eq!(decoded, params);
+    }
+
+    #[test]
+    fn test_salt_changes_key() {
+        let p1 = make_params();
+        let p2 = make_params();
+
+        let k1 = p1.derive_key("correct horse").unwrap();
+        let k2 = p2.derive_key("correct horse").unwrap();
+
+        assert_ne!(k1, k2);
+        assert_eq!(k1, p1.derive_key("correct horse").unwrap());
+    }
+}
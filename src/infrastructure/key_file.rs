@@ -0,0 +1,151 @@
+use base64ct::{Base64, Encoding};
+use rand::RngCore;
+
+use crate::domain::models::KeyFormat;
+
+use super::crypto_service::CryptoError;
+use super::header::CipherSuite;
+
+/// Префикс ключа в base64 в файле настроек; без префикса ключ читается как hex
+pub const BASE64_PREFIX: &str = "base64:";
+
+/// Размер блока в новом файле настроек
+const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Случайный ключ длины, которую требует набор алгоритмов
+pub fn generate_key(suite: CipherSuite) -> Vec<u8> {
+    let mut key = vec![0u8; suite.key_len()];
+    rand::rng().fill_bytes(&mut key);
+    key
+}
+
+/// Имя ключа в связке: латинские буквы, цифры, `-`, `_` и `.`
+pub fn validate_name(name: &str) -> Result<(), CryptoError> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if valid {
+        Ok(())
+    } else {
+        Err(CryptoError::InvalidKey(format!(
+            "недопустимое имя ключа {name}"
+        )))
+    }
+}
+
+/// Значение ключа для файла настроек
+pub fn encode_key(key: &[u8], format: KeyFormat) -> String {
+    match format {
+        KeyFormat::Base64 => format!("{BASE64_PREFIX}{}", Base64::encode_string(key)),
+        KeyFormat::Hex | KeyFormat::Raw => hex::encode(key),
+    }
+}
+
+/// Содержимое нового файла настроек. Именованный ключ становится
+/// ключом по умолчанию.
+pub fn new_credentials(name: Option<&str>, value: &str) -> String {
+    match name {
+        Some(name) => format!(
+            "chunk_size = {DEFAULT_CHUNK_SIZE}\ndefault_key = \"{name}\"\n\n[keys]\n\"{name}\" = \"{value}\"\n"
+        ),
+        None => format!("chunk_size = {DEFAULT_CHUNK_SIZE}\nkey = \"{value}\"\n"),
+    }
+}
+
+/// Добавляет именованный ключ в таблицу `[keys]` существующего файла настроек,
+/// сохраняя остальное содержимое и комментарии
+pub fn add_to_keyring(content: &str, name: &str, value: &str) -> Result<String, CryptoError> {
+    let table: toml::Table = content
+        .parse()
+        .map_err(|e| CryptoError::InvalidKey(format!("не удалось разобрать файл настроек: {e}")))?;
+
+    let entry = format!("\"{name}\" = \"{value}\"\n");
+    let Some(keys) = table.get("keys") else {
+        let separator = if content.is_empty() || content.ends_with('\n') {
+            ""
+        } else {
+            "\n"
+        };
+        return Ok(format!("{content}{separator}\n[keys]\n{entry}"));
+    };
+
+    let keys = keys.as_table().ok_or(CryptoError::InvalidKey(
+        "keys в файле настроек должен быть таблицей".into(),
+    ))?;
+    if keys.contains_key(name) {
+        return Err(CryptoError::InvalidKey(format!(
+            "ключ {name} уже есть в связке"
+        )));
+    }
+
+    let mut out = String::with_capacity(content.len() + entry.len());
+    let mut inserted = false;
+    for line in content.split_inclusive('\n') {
+        out.push_str(line);
+        if !inserted && line.trim() == "[keys]" {
+            if !line.ends_with('\n') {
+                out.push('\n');
+            }
+            out.push_str(&entry);
+            inserted = true;
+        }
+    }
+
+    if !inserted {
+        return Err(CryptoError::InvalidKey(
+            "связка keys должна быть задана отдельной таблицей [keys]".into(),
+        ));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_credentials() {
+        let content = new_credentials(Some("2025"), "abcd");
+        let table: toml::Table = content.parse().unwrap();
+
+        assert_eq!(table["default_key"].as_str(), Some("2025"));
+        assert_eq!(table["keys"]["2025"].as_str(), Some("abcd"));
+    }
+
+    #[test]
+    fn test_add_to_keyring() {
+        let content =
+            "# настройки\nchunk_size = 4096\n\n[keys]\nold = \"01\"\n\n[kdf]\niterations = 3\n";
+
+        let updated = add_to_keyring(content, "new", "02").unwrap();
+        let table: toml::Table = updated.parse().unwrap();
+
+        assert!(updated.starts_with("# настройки\n"));
+        assert_eq!(table["keys"]["old"].as_str(), Some("01"));
+        assert_eq!(table["keys"]["new"].as_str(), Some("02"));
+        assert_eq!(table["kdf"]["iterations"].as_integer(), Some(3));
+    }
+
+    #[test]
+    fn test_add_to_keyring_without_keys() {
+        let updated = add_to_keyring("chunk_size = 4096", "new", "02").unwrap();
+        let table: toml::Table = updated.parse().unwrap();
+
+        assert_eq!(table["keys"]["new"].as_str(), Some("02"));
+    }
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("2025-backup").is_ok());
+        assert!(validate_name("a\" = \"b").is_err());
+        assert!(validate_name("").is_err());
+    }
+
+    #[test]
+    fn test_add_existing_name() {
+        let content = "chunk_size = 4096\n[keys]\nold = \"01\"\n";
+
+        assert!(add_to_keyring(content, "old", "02").is_err());
+    }
+}
@@ -0,0 +1,392 @@
+use aes_kw::KekAes256;
+use rand::RngCore;
+
+use crate::domain::models::Credentials;
+
+use super::crypto_service::CryptoError;
+use super::header::{FileHeader, TAG_KDF, TAG_KEY_ID, TAG_RECIPIENT, TAG_WRAPPED_KEY};
+use super::kdf::KdfParams;
+use super::keyring::{Keyring, key_id};
+use super::recipients::{Identity, Recipient, stanza_recipient_id};
+
+const WRAPPED_KEY_LEN: usize = 40;
+
+/// Получает ключи файла по настройкам и полям заголовка.
+///
+/// Данные каждого файла шифруются случайным ключом данных (DEK), который
+/// заворачивается мастер-ключом по AES-KW и хранится в поле `TAG_WRAPPED_KEY`.
+/// Мастер-ключ берётся из связки (`TAG_KEY_ID`) или из пароля (`TAG_KDF`).
+/// Если заданы получатели X25519, ключ данных вместо этого заворачивается
+/// для каждого из них в отдельное поле `TAG_RECIPIENT`.
+pub struct KeyResolver {
+    credentials: Credentials,
+    keyring: Keyring,
+}
+
+impl From<Credentials> for KeyResolver {
+    fn from(credentials: Credentials) -> Self {
+        Self {
+            keyring: Keyring::from(&credentials),
+            credentials,
+        }
+    }
+}
+
+impl KeyResolver {
+    /// Мастер-ключ для нового файла. При шифровании паролем в заголовок
+    /// записываются параметры Argon2id и случайная соль, иначе
+    /// отпечаток ключа по умолчанию из связки.
+    fn master_key_for_encrypt(&self, header: &mut FileHeader) -> Result<[u8; 32], CryptoError> {
+        match &self.credentials.passphrase {
+            Some(passphrase) => {
+                let params = KdfParams::generate(&self.credentials.kdf);
+                header.fields.push((TAG_KDF, params.encode()));
+                params.derive_key(passphrase)
+            }
+            None => {
+                let key = self.keyring.default_key()?;
+                header.fields.push((TAG_KEY_ID, key_id(&key).to_vec()));
+                Ok(key)
+            }
+        }
+    }
+
+    fn master_key_for_decrypt(&self, header: &FileHeader) -> Result<[u8; 32], CryptoError> {
+        match header.field(TAG_KDF) {
+            Some(kdf) => {
+                let passphrase =
+                    self.credentials
+                        .passphrase
+                        .as_ref()
+                        .ok_or(CryptoError::KeyUnavailable(
+                            "файл зашифрован паролем, укажите --passphrase или CRYPTO_PASSPHRASE"
+                                .into(),
+                        ))?;
+                KdfParams::decode(kdf)?.derive_key(passphrase)
+            }
+            None => match header.field(TAG_KEY_ID) {
+                Some(id) => self.keyring.find(id),
+                None => self.keyring.default_key(),
+            },
+        }
+    }
+
+    /// Создаёт случайный ключ данных и записывает его в заголовок,
+    /// завернув мастер-ключом
+    pub fn file_key_for_encrypt(&self, header: &mut FileHeader) -> Result<[u8; 32], CryptoError> {
+        let mut file_key = [0u8; 32];
+        rand::rng().fill_bytes(&mut file_key);
+
+        if !self.credentials.recipients.is_empty() {
+            for recipient in &self.credentials.recipients {
+                let stanza = Recipient::parse(recipient)?.wrap(&file_key)?;
+                header.fields.push((TAG_RECIPIENT, stanza));
+            }
+            return Ok(file_key);
+        }
+
+        let master_key = self.master_key_for_encrypt(header)?;
+
+        header
+            .fields
+            .push((TAG_WRAPPED_KEY, wrap_key(&master_key, &file_key)?));
+        Ok(file_key)
+    }
+
+    /// Ключ данных файла. Сначала проверяются поля получателей, затем
+    /// мастер-ключ. Файлы без `TAG_WRAPPED_KEY` и получателей зашифрованы
+    /// непосредственно мастер-ключом.
+    pub fn file_key_for_decrypt(&self, header: &FileHeader) -> Result<[u8; 32], CryptoError> {
+        if let Some(file_key) = self.file_key_from_identities(header)? {
+            return Ok(file_key);
+        }
+        if header.field(TAG_RECIPIENT).is_some() && header.field(TAG_WRAPPED_KEY).is_none() {
+            return Err(CryptoError::KeyUnavailable(
+                "файл зашифрован для получателей X25519, укажите подходящий --identity".into(),
+            ));
+        }
+
+        let master_key = self.master_key_for_decrypt(header)?;
+
+        match header.field(TAG_WRAPPED_KEY) {
+            Some(wrapped) => unwrap_key(&master_key, wrapped),
+            None => Ok(master_key),
+        }
+    }
+
+    fn file_key_from_identities(
+        &self,
+        header: &FileHeader,
+    ) -> Result<Option<[u8; 32]>, CryptoError> {
+        for identity in &self.credentials.identities {
+            let identity = Identity::parse(identity)?;
+            for stanza in header.fields_with(TAG_RECIPIENT) {
+                if let Some(file_key) = identity.unwrap(stanza)? {
+                    return Ok(Some(file_key));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Ключ данных файла, который можно завернуть для других получателей.
+    /// Файлы, зашифрованные непосредственно мастер-ключом, не подходят:
+    /// иначе получатели узнали бы сам мастер-ключ.
+    fn shareable_file_key(&self, header: &FileHeader) -> Result<[u8; 32], CryptoError> {
+        if header.field(TAG_WRAPPED_KEY).is_none() && header.field(TAG_RECIPIENT).is_none() {
+            return Err(CryptoError::InvalidHeader(
+                "Файл не содержит завёрнутого ключа".into(),
+            ));
+        }
+        self.file_key_for_decrypt(header)
+    }
+
+    /// Заворачивает ключ данных для новых получателей. Уже добавленные
+    /// получатели пропускаются.
+    pub fn add_recipients(
+        &self,
+        header: &FileHeader,
+        recipients: &[String],
+    ) -> Result<FileHeader, CryptoError> {
+        let file_key = self.shareable_file_key(header)?;
+
+        let mut new_header = header.clone();
+        for recipient in recipients {
+            let recipient = Recipient::parse(recipient)?;
+            let exists = new_header
+                .fields_with(TAG_RECIPIENT)
+                .any(|stanza| stanza_recipient_id(stanza) == recipient.id());
+            if !exists {
+                new_header
+                    .fields
+                    .push((TAG_RECIPIENT, recipient.wrap(&file_key)?));
+            }
+        }
+        Ok(new_header)
+    }
+
+    /// Удаляет поля получателей. Доступ к ключу данных проверяется так же,
+    /// как при добавлении, а удалить последний способ получить ключ нельзя.
+    pub fn remove_recipients(
+        &self,
+        header: &FileHeader,
+        recipients: &[String],
+    ) -> Result<FileHeader, CryptoError> {
+        self.shareable_file_key(header)?;
+
+        let mut new_header = header.clone();
+        for recipient in recipients {
+            let id = Recipient::parse(recipient)?.id();
+            let before = new_header.fields.len();
+            new_header
+                .fields
+                .retain(|(tag, stanza)| *tag != TAG_RECIPIENT || stanza_recipient_id(stanza) != id);
+            if new_header.fields.len() == before {
+                return Err(CryptoError::KeyUnavailable(format!(
+                    "получатель {recipient} отсутствует в файле"
+                )));
+            }
+        }
+
+        if new_header.field(TAG_RECIPIENT).is_none() && new_header.field(TAG_WRAPPED_KEY).is_none()
+        {
+            return Err(CryptoError::KeyUnavailable(
+                "нельзя удалить последнего получателя файла".into(),
+            ));
+        }
+        Ok(new_header)
+    }
+
+    /// Описывает, кто может получить ключ данных файла. Получатели
+    /// из настроек и identity показываются открытым ключом, остальные отпечатком.
+    pub fn describe(&self, header: &FileHeader) -> Vec<String> {
+        let known: Vec<Recipient> = self
+            .credentials
+            .recipients
+            .iter()
+            .filter_map(|r| Recipient::parse(r).ok())
+            .chain(
+                self.credentials
+                    .identities
+                    .iter()
+                    .filter_map(|i| Identity::parse(i).ok())
+                    .map(|i| i.recipient()),
+            )
+            .collect();
+
+        let mut lines: Vec<String> = header
+            .fields_with(TAG_RECIPIENT)
+            .map(|stanza| {
+                let id = stanza_recipient_id(stanza);
+                match known.iter().find(|r| r.id() == id) {
+                    Some(recipient) => recipient.to_string(),
+                    None => format!("x25519 с отпечатком {}", hex::encode(id)),
+                }
+            })
+            .collect();
+
+        if header.field(TAG_WRAPPED_KEY).is_some() {
+            lines.push(match (header.field(TAG_KDF), header.field(TAG_KEY_ID)) {
+                (Some(_), _) => "мастер-ключ из пароля".to_string(),
+                (None, Some(id)) => format!("мастер-ключ с отпечатком {}", hex::encode(id)),
+                (None, None) => "мастер-ключ".to_string(),
+            });
+        }
+        lines
+    }
+
+    /// Заворачивает ключ данных файла мастер-ключом для шифрования из `target`.
+    /// Ключ данных и тело файла, а с ними и префикс nonce, не меняются.
+    pub fn rewrap(&self, header: &FileHeader, target: &Self) -> Result<FileHeader, CryptoError> {
+        let wrapped = header
+            .field(TAG_WRAPPED_KEY)
+            .ok_or(CryptoError::InvalidHeader(
+                "Файл не содержит завёрнутого ключа".into(),
+            ))?;
+        let file_key = unwrap_key(&self.master_key_for_decrypt(header)?, wrapped)?;
+
+        let mut new_header = header.clone();
+        new_header.remove(TAG_KDF);
+        new_header.remove(TAG_KEY_ID);
+        new_header.remove(TAG_WRAPPED_KEY);
+
+        let master_key = target.master_key_for_encrypt(&mut new_header)?;
+        new_header
+            .fields
+            .push((TAG_WRAPPED_KEY, wrap_key(&master_key, &file_key)?));
+        Ok(new_header)
+    }
+}
+
+pub(super) fn wrap_key(master_key: &[u8; 32], file_key: &[u8; 32]) -> Result<Vec<u8>, CryptoError> {
+    let mut wrapped = vec![0u8; WRAPPED_KEY_LEN];
+    KekAes256::from(*master_key)
+        .wrap(file_key, &mut wrapped)
+        .map_err(|e| CryptoError::AesError(e.to_string()))?;
+    Ok(wrapped)
+}
+
+pub(super) fn unwrap_key(master_key: &[u8; 32], wrapped: &[u8]) -> Result<[u8; 32], CryptoError> {
+    if wrapped.len() != WRAPPED_KEY_LEN {
+        return Err(CryptoError::InvalidHeader(
+            "Некорректная длина завёрнутого ключа".into(),
+        ));
+    }
+
+    let mut file_key = [0u8; 32];
+    KekAes256::from(*master_key)
+        .unwrap(wrapped, &mut file_key)
+        .map_err(|_| CryptoError::WrongKey)?;
+    Ok(file_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infrastructure::header::CipherSuite;
+    use std::collections::BTreeMap;
+
+    fn make_resolver(keys: &[(&str, u8)], default_key: &str) -> KeyResolver {
+        KeyResolver::from(Credentials {
+            keys: keys
+                .iter()
+                .map(|(name, byte)| (name.to_string(), vec![*byte; 32]))
+                .collect::<BTreeMap<_, _>>(),
+            default_key: Some(default_key.into()),
+            chunk_size: 8,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_file_key_round_trip() {
+        let resolver = make_resolver(&[("old", 1)], "old");
+        let mut header = FileHeader::new(CipherSuite::Aes256GcmStream, 8);
+
+        let file_key = resolver.file_key_for_encrypt(&mut header).unwrap();
+
+        assert_ne!(file_key, [1; 32]);
+        assert_eq!(resolver.file_key_for_decrypt(&header).unwrap(), file_key);
+    }
+
+    // После переупаковки файл открывается только новым мастер-ключом,
+    // а ключ данных остаётся прежним
+    #[test]
+    fn test_rewrap() {
+        let old = make_resolver(&[("old", 1)], "old");
+        let both = make_resolver(&[("old", 1), ("new", 2)], "new");
+        let new = make_resolver(&[("new", 2)], "new");
+        let mut header = FileHeader::new(CipherSuite::Aes256GcmStream, 8);
+        let file_key = old.file_key_for_encrypt(&mut header).unwrap();
+
+        let rewrapped = both.rewrap(&header, &both).unwrap();
+
+        assert_eq!(new.file_key_for_decrypt(&rewrapped).unwrap(), file_key);
+        assert!(old.file_key_for_decrypt(&rewrapped).is_err());
+    }
+
+    #[test]
+    fn test_file_key_for_recipients() {
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        let mallory = Identity::generate();
+        let mut enc = make_resolver(&[], "");
+        enc.credentials.recipients =
+            vec![alice.recipient().to_string(), bob.recipient().to_string()];
+        let mut header = FileHeader::new(CipherSuite::Aes256GcmStream, 8);
+
+        let file_key = enc.file_key_for_encrypt(&mut header).unwrap();
+
+        for identity in [&alice, &bob] {
+            let mut dec = make_resolver(&[], "");
+            dec.credentials.identities = vec![identity.encode()];
+            assert_eq!(dec.file_key_for_decrypt(&header).unwrap(), file_key);
+        }
+
+        let mut dec = make_resolver(&[], "");
+        dec.credentials.identities = vec![mallory.encode()];
+        assert!(matches!(
+            dec.file_key_for_decrypt(&header),
+            Err(CryptoError::KeyUnavailable(_))
+        ));
+    }
+
+    //Проверяем, что добавленный получатель открывает файл, а удалённый нет
+    #[test]
+    fn test_add_remove_recipients() {
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        let owner = make_resolver(&[("old", 1)], "old");
+        let mut header = FileHeader::new(CipherSuite::Aes256GcmStream, 8);
+        let file_key = owner.file_key_for_encrypt(&mut header).unwrap();
+
+        let bob_recipient = vec![bob.recipient().to_string()];
+        let header = owner.add_recipients(&header, &bob_recipient).unwrap();
+        let mut as_bob = make_resolver(&[], "");
+        as_bob.credentials.identities = vec![bob.encode()];
+        assert_eq!(as_bob.file_key_for_decrypt(&header).unwrap(), file_key);
+
+        let mut as_alice = make_resolver(&[], "");
+        as_alice.credentials.identities = vec![alice.encode()];
+        let alice_recipient = vec![alice.recipient().to_string()];
+        assert!(as_alice.add_recipients(&header, &alice_recipient).is_err());
+
+        let header = as_bob.remove_recipients(&header, &bob_recipient).unwrap();
+        assert!(as_bob.file_key_for_decrypt(&header).is_err());
+        assert_eq!(owner.file_key_for_decrypt(&header).unwrap(), file_key);
+    }
+
+    #[test]
+    fn test_remove_last_recipient() {
+        let alice = Identity::generate();
+        let mut resolver = make_resolver(&[], "");
+        resolver.credentials.recipients = vec![alice.recipient().to_string()];
+        resolver.credentials.identities = vec![alice.encode()];
+        let mut header = FileHeader::new(CipherSuite::Aes256GcmStream, 8);
+        resolver.file_key_for_encrypt(&mut header).unwrap();
+
+        let result = resolver.remove_recipients(&header, &resolver.credentials.recipients);
+
+        assert!(result.is_err());
+    }
+}
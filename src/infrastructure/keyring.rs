@@ -0,0 +1,170 @@
+use sha2::{Digest, Sha256};
+
+use crate::domain::models::Credentials;
+
+use super::crypto_service::CryptoError;
+
+pub const KEY_ID_LEN: usize = 8;
+
+/// Имя ключа `key` из корня настроек в связке
+const LEGACY_KEY_NAME: &str = "key";
+
+/// Отпечаток ключа, записываемый в заголовок в поле `TAG_KEY_ID`
+pub fn key_id(key: &[u8; 32]) -> [u8; KEY_ID_LEN] {
+    let digest = Sha256::new()
+        .chain_update(b"crypto_files key id")
+        .chain_update(key)
+        .finalize();
+
+    let mut id = [0u8; KEY_ID_LEN];
+    id.copy_from_slice(&digest[..KEY_ID_LEN]);
+    id
+}
+
+fn to_key(name: &str, key_slice: &[u8]) -> Result<[u8; 32], CryptoError> {
+    <[u8; 32]>::try_from(key_slice).map_err(|_| {
+        CryptoError::InvalidKey(format!(
+            "ключ {name} должен быть длиной 32 байта, получено {}",
+            key_slice.len()
+        ))
+    })
+}
+
+/// Набор ключей из настроек: именованные ключи из `keys` и ключ `key`
+pub struct Keyring {
+    keys: Vec<(String, Vec<u8>)>,
+    default_name: Option<String>,
+}
+
+impl From<&Credentials> for Keyring {
+    fn from(credentials: &Credentials) -> Self {
+        let mut keys: Vec<(String, Vec<u8>)> = credentials
+            .keys
+            .iter()
+            .map(|(name, key)| (name.clone(), key.clone()))
+            .collect();
+
+        if let Some(key) = &credentials.key {
+            keys.push((LEGACY_KEY_NAME.to_string(), key.clone()));
+        }
+
+        let default_name = credentials.default_key.clone().or_else(|| {
+            match (credentials.key.is_some(), keys.len()) {
+                (true, _) => Some(LEGACY_KEY_NAME.to_string()),
+                (false, 1) => Some(keys[0].0.clone()),
+                _ => None,
+            }
+        });
+
+        Self { keys, default_name }
+    }
+}
+
+impl Keyring {
+    /// Имена ключей связки: имена из `keys` и `key` для ключа из корня настроек
+    pub fn names(credentials: &Credentials) -> impl Iterator<Item = &str> {
+        credentials
+            .keys
+            .keys()
+            .map(String::as_str)
+            .chain(credentials.key.as_ref().map(|_| LEGACY_KEY_NAME))
+    }
+
+    /// Ключ для шифрования новых файлов
+    pub fn default_key(&self) -> Result<[u8; 32], CryptoError> {
+        let name = self
+            .default_name
+            .as_ref()
+            .ok_or(CryptoError::KeyUnavailable(
+                "не выбран ключ по умолчанию, укажите default_key или --key-id".into(),
+            ))?;
+
+        let (_, key) =
+            self.keys
+                .iter()
+                .find(|(n, _)| n == name)
+                .ok_or(CryptoError::KeyUnavailable(format!(
+                    "ключ {name} отсутствует в связке"
+                )))?;
+
+        to_key(name, key)
+    }
+
+    /// Ищет ключ по отпечатку из заголовка файла. Если такого ключа
+    /// в связке нет, файл зашифрован другим ключом.
+    pub fn find(&self, id: &[u8]) -> Result<[u8; 32], CryptoError> {
+        for (name, key) in &self.keys {
+            let key = to_key(name, key)?;
+            if key_id(&key) == id {
+                return Ok(key);
+            }
+        }
+
+        Err(CryptoError::WrongKey)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_default_key() {
+        let keyring = Keyring::from(&Credentials {
+            keys: BTreeMap::from([
+                ("old".to_string(), vec![1; 32]),
+                ("new".to_string(), vec![2; 32]),
+            ]),
+            default_key: Some("new".into()),
+            ..Default::default()
+        });
+
+        assert_eq!(keyring.default_key().unwrap(), [2; 32]);
+    }
+
+    #[test]
+    fn test_find_by_key_id() {
+        let keyring = Keyring::from(&Credentials {
+            keys: BTreeMap::from([("old".to_string(), vec![1; 32])]),
+            ..Default::default()
+        });
+
+        assert_eq!(keyring.find(&key_id(&[1; 32])).unwrap(), [1; 32]);
+        assert!(matches!(
+            keyring.find(&key_id(&[3; 32])),
+            Err(CryptoError::WrongKey)
+        ));
+    }
+
+    #[test]
+    fn test_no_default_key() {
+        let keyring = Keyring::from(&Credentials {
+            keys: BTreeMap::from([
+                ("old".to_string(), vec![1; 32]),
+                ("new".to_string(), vec![2; 32]),
+            ]),
+            ..Default::default()
+        });
+
+        assert!(keyring.default_key().is_err());
+    }
+
+    //Проверяем, что ключ неверной длины не дополняется нулями, а даёт ошибку
+    #[test]
+    fn test_invalid_key_length() {
+        let keyring = Keyring::from(&Credentials {
+            keys: BTreeMap::from([("short".to_string(), vec![1; 16])]),
+            ..Default::default()
+        });
+
+        assert!(matches!(
+            keyring.default_key(),
+            Err(CryptoError::InvalidKey(_))
+        ));
+        assert!(matches!(
+            keyring.find(&key_id(&[1; 32])),
+            Err(CryptoError::InvalidKey(_))
+        ));
+    }
+}
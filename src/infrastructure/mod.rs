@@ -1,3 +1,16 @@
+pub mod aead_crypto_service;
+pub mod auto_crypto_service;
 pub mod crypto_service;
+pub mod file_metadata;
 pub mod file_service;
+pub mod header;
+pub mod header_rewriter;
+pub mod kdf;
+pub mod key_file;
+pub mod key_resolver;
+pub mod keyring;
+pub mod orphan_temp;
+pub mod output_file_service;
+pub mod recipients;
+pub mod stream_file_service;
 pub mod terminal_service;
@@ -0,0 +1,291 @@
+use std::ffi::OsString;
+use std::fs::{File, remove_file};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use uuid::Uuid;
+
+use crate::domain::models::PreserveOptions;
+
+use super::file_metadata::FileMetadata;
+use super::file_service::{copy_into_place, create_private_file, move_file, sync_parent_dir};
+
+/// Окончание имени файла с путём исходного файла для временного файла в `temp_dir`
+const ORIGIN_SUFFIX: &str = ".origin";
+
+/// Файл `<временный файл>.origin` с путём исходного файла
+fn origin_path(temp_path: &Path) -> PathBuf {
+    let mut name = temp_path.file_name().unwrap_or_default().to_os_string();
+    name.push(ORIGIN_SUFFIX);
+    temp_path.with_file_name(name)
+}
+
+/// Записывает рядом с временным файлом абсолютный путь исходного,
+/// когда временный файл создаётся не в каталоге исходного
+pub fn write_origin(temp_path: &Path, original: &Path) -> io::Result<()> {
+    let original = std::path::absolute(original)?.into_os_string();
+    #[cfg(unix)]
+    let data = std::os::unix::ffi::OsStringExt::into_vec(original);
+    #[cfg(not(unix))]
+    let data = original.to_string_lossy().into_owned().into_bytes();
+
+    let mut file = create_private_file(&origin_path(temp_path))?;
+    file.write_all(&data)?;
+    file.sync_all()
+}
+
+/// Удаляет запись о пути исходного файла
+pub fn remove_origin(temp_path: &Path) -> io::Result<()> {
+    match remove_file(origin_path(temp_path)) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn read_origin(temp_path: &Path) -> Option<PathBuf> {
+    let data = std::fs::read(origin_path(temp_path)).ok()?;
+    #[cfg(unix)]
+    let original: OsString = std::os::unix::ffi::OsStringExt::from_vec(data);
+    #[cfg(not(unix))]
+    let original = OsString::from(String::from_utf8(data).ok()?);
+    Some(PathBuf::from(original))
+}
+
+/// Временный файл, оставшийся после прерванной обработки
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrphanTemp {
+    pub path: PathBuf,
+    /// Исходный файл, который должен был быть заменён
+    pub original: PathBuf,
+}
+
+impl OrphanTemp {
+    /// Разбирает имя `<имя>.<uuid>.tmp`. Путь исходного файла для временного
+    /// файла из `temp_dir` берётся из записи `<временный файл>.origin`.
+    /// Прежние версии заменяли расширение исходного файла, поэтому при
+    /// отсутствии `<имя>` исходным считается единственный файл рядом с тем же
+    /// именем без расширения.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?;
+        let (base, uuid) = name.strip_suffix(".tmp")?.rsplit_once('.')?;
+        Uuid::parse_str(uuid).ok()?;
+
+        if let Some(original) = read_origin(path) {
+            return Some(Self {
+                path: path.to_path_buf(),
+                original,
+            });
+        }
+
+        let dir = path.parent()?;
+        let mut original = dir.join(base);
+        if !original.exists()
+            && let Some(legacy) = Self::legacy_original(dir, base)
+        {
+            original = legacy;
+        }
+
+        Some(Self {
+            path: path.to_path_buf(),
+            original,
+        })
+    }
+
+    fn legacy_original(dir: &Path, base: &str) -> Option<PathBuf> {
+        let mut candidates = std::fs::read_dir(dir)
+            .ok()?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.is_file()
+                    && path.extension().is_some_and(|ext| ext != "tmp")
+                    && path.file_stem().and_then(|stem| stem.to_str()) == Some(base)
+            });
+
+        let candidate = candidates.next()?;
+        candidates.next().is_none().then_some(candidate)
+    }
+
+    pub fn original_exists(&self) -> bool {
+        self.original.is_file()
+    }
+
+    /// Завершает прерванную замену: переносит атрибуты исходного файла,
+    /// сбрасывает временный файл на диск и переименовывает его поверх исходного.
+    /// Временный файл с другой файловой системы сначала копируется к исходному.
+    pub fn replace(&self, preserve: PreserveOptions) -> io::Result<()> {
+        let metadata = match self.original_exists() {
+            true => Some(FileMetadata::capture(&self.original, preserve)?),
+            false => None,
+        };
+        // Атрибуты могли быть перенесены до сбоя, поэтому временный файл
+        // открывается только на чтение
+        let file = File::open(&self.path)?;
+        if let Some(metadata) = &metadata {
+            metadata.apply(&file, preserve)?;
+        }
+        file.sync_all()?;
+        drop(file);
+
+        self.move_to(&self.original, true, |file| match &metadata {
+            Some(metadata) => metadata.apply(file, preserve),
+            None => Ok(()),
+        })?;
+        sync_parent_dir(&self.original)
+    }
+
+    pub fn delete(&self) -> io::Result<()> {
+        remove_file(&self.path)?;
+        remove_origin(&self.path)?;
+        sync_parent_dir(&self.path)
+    }
+
+    /// Переименовывает временный файл в `target`, а если он на другой
+    /// файловой системе, копирует его туда и удаляет
+    fn move_to(
+        &self,
+        target: &Path,
+        overwrite: bool,
+        apply: impl FnOnce(&File) -> io::Result<()>,
+    ) -> io::Result<()> {
+        match move_file(&self.path, target, overwrite) {
+            Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                copy_into_place(&self.path, target, overwrite, apply)?;
+                remove_file(&self.path)?;
+            }
+            result => result?,
+        }
+        remove_origin(&self.path)
+    }
+
+    /// Переименовывает временный файл в `<исходное имя>.recovered`, не трогая
+    /// исходный, и возвращает новый путь
+    pub fn keep(&self) -> io::Result<PathBuf> {
+        let name = self.original.file_name().unwrap_or_default().to_os_string();
+        let mut number = 0;
+        let kept = loop {
+            let mut kept_name = name.clone();
+            match number {
+                0 => kept_name.push(".recovered"),
+                n => kept_name.push(format!(".recovered.{n}")),
+            }
+            let kept = self.original.with_file_name(kept_name);
+            if !kept.exists() {
+                break kept;
+            }
+            number += 1;
+        };
+
+        self.move_to(&kept, false, |_| Ok(()))?;
+        sync_parent_dir(&kept)?;
+        Ok(kept)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::services::FileService;
+    use crate::infrastructure::file_service::LocalFileService;
+    use std::fs;
+
+    fn make_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("crypto_files_{name}_{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    //Проверяем, что по имени временного файла находится исходный, в том числе для старых имён
+    #[test]
+    fn test_from_path() {
+        let dir = make_dir("orphan");
+        fs::write(dir.join("a.txt"), b"a").unwrap();
+        fs::write(dir.join("b.txt"), b"b").unwrap();
+        let uuid = uuid::Uuid::new_v4();
+
+        let orphan = OrphanTemp::from_path(&dir.join(format!("a.txt.{uuid}.tmp"))).unwrap();
+        assert_eq!(orphan.original, dir.join("a.txt"));
+
+        let legacy = OrphanTemp::from_path(&dir.join(format!("b.{uuid}.tmp"))).unwrap();
+        assert_eq!(legacy.original, dir.join("b.txt"));
+
+        let lost = OrphanTemp::from_path(&dir.join(format!("c.txt.{uuid}.tmp"))).unwrap();
+        assert!(!lost.original_exists());
+
+        assert!(OrphanTemp::from_path(&dir.join("notes.tmp")).is_none());
+        assert!(OrphanTemp::from_path(&dir.join("a.txt.1234.tmp")).is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    //Проверяем, что сохранённый временный файл не затирает исходный и прежние копии
+    #[test]
+    fn test_keep() {
+        let dir = make_dir("orphan_keep");
+        fs::write(dir.join("a.txt"), b"original").unwrap();
+        fs::write(dir.join("a.txt.recovered"), b"old copy").unwrap();
+        let path = temp_path(&dir);
+        fs::write(&path, b"temp").unwrap();
+
+        let kept = OrphanTemp::from_path(&path).unwrap().keep().unwrap();
+
+        assert_eq!(kept, dir.join("a.txt.recovered.1"));
+        assert_eq!(fs::read(kept).unwrap(), b"temp");
+        assert_eq!(fs::read(dir.join("a.txt")).unwrap(), b"original");
+        assert!(!path.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    //Проверяем, что замена переносит содержимое временного файла в исходный
+    #[test]
+    fn test_replace() {
+        let dir = make_dir("orphan_replace");
+        fs::write(dir.join("a.txt"), b"original").unwrap();
+        let path = temp_path(&dir);
+        fs::write(&path, b"temp").unwrap();
+
+        OrphanTemp::from_path(&path)
+            .unwrap()
+            .replace(PreserveOptions::default())
+            .unwrap();
+
+        assert_eq!(fs::read(dir.join("a.txt")).unwrap(), b"temp");
+        assert!(!path.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    //Проверяем, что временный файл из temp_dir заменяет исходный файл
+    //в другом каталоге, а не файл с тем же именем в temp_dir
+    #[test]
+    fn test_replace_from_temp_dir() {
+        let dir = make_dir("orphan_data");
+        let temp_dir = make_dir("orphan_temp_dir");
+        let original = dir.join("a.txt");
+        fs::write(&original, b"original").unwrap();
+
+        let mut service =
+            LocalFileService::new(16, &original).with_temp_dir(Some(temp_dir.clone()));
+        service.init_original().unwrap();
+        service.make_temp().unwrap();
+        service.write_chunk(b"temp".to_vec()).unwrap();
+        drop(service);
+
+        let temp = fs::read_dir(&temp_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().is_some_and(|ext| ext == "tmp"))
+            .unwrap();
+        let orphan = OrphanTemp::from_path(&temp).unwrap();
+        assert_eq!(orphan.original, original);
+
+        orphan.replace(PreserveOptions::default()).unwrap();
+
+        assert_eq!(fs::read(&original).unwrap(), b"temp");
+        assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(temp_dir).unwrap();
+    }
+
+    fn temp_path(dir: &Path) -> PathBuf {
+        super::super::file_service::temp_path_for(&dir.join("a.txt"))
+    }
+}
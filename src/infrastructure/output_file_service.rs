@@ -0,0 +1,166 @@
+use std::path::PathBuf;
+
+use crate::domain::services::FileService;
+
+use super::file_service::{FsError, LocalFileService};
+
+/// Записывает результат в отдельный файл, исходный файл не меняется.
+///
+/// Существующий файл перезаписывается, только если задан `force`.
+/// Он проверяется до обработки, а без `force` результат переносится на место
+/// без замены, поэтому файл, появившийся во время обработки, не затирается.
+pub struct OutputFileService {
+    inner: LocalFileService,
+    output_path: PathBuf,
+    force: bool,
+}
+
+impl OutputFileService {
+    pub fn new(inner: LocalFileService, output_path: impl Into<PathBuf>, force: bool) -> Self {
+        let output_path = output_path.into();
+        Self {
+            inner: inner.with_destination(output_path.clone(), force),
+            output_path,
+            force,
+        }
+    }
+
+    fn check_overwrite(&self) -> Result<(), FsError> {
+        if !self.force && self.output_path.exists() {
+            return Err(FsError::OutputExists(self.output_path.clone()));
+        }
+        Ok(())
+    }
+}
+
+impl FileService for OutputFileService {
+    type Error = FsError;
+
+    fn init_original(&mut self) -> Result<&mut Self, Self::Error> {
+        self.check_overwrite()?;
+        self.inner.init_original()?;
+        Ok(self)
+    }
+
+    fn make_temp(&mut self) -> Result<&mut Self, Self::Error> {
+        self.inner.make_temp()?;
+        Ok(self)
+    }
+
+    fn commit(&mut self) -> Result<&mut Self, Self::Error> {
+        match self.inner.commit() {
+            Ok(_) => Ok(self),
+            Err(FsError::Io(e)) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                Err(FsError::OutputExists(self.output_path.clone()))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn revert(&mut self) -> Result<&mut Self, Self::Error> {
+        self.inner.revert()?;
+        Ok(self)
+    }
+
+    fn read_chunk_original(&mut self) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.inner.read_chunk_original()
+    }
+
+    fn read_exact_original(&mut self, len: usize) -> Result<Vec<u8>, Self::Error> {
+        self.inner.read_exact_original(len)
+    }
+
+    fn write_chunk(&mut self, chunk: Vec<u8>) -> Result<(), Self::Error> {
+        self.inner.write_chunk(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use uuid::Uuid;
+
+    fn make_file(name: &str, data: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("crypto_files_{name}_{}", Uuid::new_v4()));
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    fn copy(input: &PathBuf, output: &PathBuf, force: bool) -> Result<(), FsError> {
+        copy_racing(input, output, force, || ())
+    }
+
+    /// Копирует файл, вызывая `race` перед заменой
+    fn copy_racing(
+        input: &PathBuf,
+        output: &PathBuf,
+        force: bool,
+        race: impl FnOnce(),
+    ) -> Result<(), FsError> {
+        let mut service = OutputFileService::new(LocalFileService::new(16, input), output, force);
+        service.init_original()?;
+        service.make_temp()?;
+        let chunk = service.read_chunk_original()?.unwrap_or_default();
+        service.write_chunk(chunk)?;
+        race();
+        service.commit().map(|_| ())
+    }
+
+    //Проверяем, что результат записывается в новый файл, а исходный остаётся
+    #[test]
+    fn test_output_keeps_original() {
+        let input = make_file("input", b"secret");
+        let output = input.with_file_name(format!("crypto_files_output_{}", Uuid::new_v4()));
+
+        copy(&input, &output, false).unwrap();
+
+        assert_eq!(fs::read(&input).unwrap(), b"secret");
+        assert_eq!(fs::read(&output).unwrap(), b"secret");
+        fs::remove_file(input).unwrap();
+        fs::remove_file(output).unwrap();
+    }
+
+    //Проверяем, что существующий файл перезаписывается только с force
+    #[test]
+    fn test_overwrite_protection() {
+        let input = make_file("input", b"secret");
+        let output = make_file("output", b"old");
+
+        let result = copy(&input, &output, false);
+        assert!(matches!(result, Err(FsError::OutputExists(_))));
+        assert_eq!(fs::read(&output).unwrap(), b"old");
+
+        copy(&input, &output, true).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), b"secret");
+        fs::remove_file(input).unwrap();
+        fs::remove_file(output).unwrap();
+    }
+
+    //Проверяем, что файл, появившийся во время обработки, не затирается без force,
+    //а временный файл удаляется
+    #[test]
+    fn test_output_created_during_processing() {
+        let input = make_file("input", b"secret");
+        let output = input.with_file_name(format!("crypto_files_output_{}", Uuid::new_v4()));
+
+        let result = copy_racing(&input, &output, false, || {
+            fs::write(&output, b"other").unwrap();
+        });
+
+        assert!(matches!(result, Err(FsError::OutputExists(_))));
+        assert_eq!(fs::read(&output).unwrap(), b"other");
+        let prefix = output.file_name().unwrap().to_string_lossy().into_owned();
+        let leftovers = fs::read_dir(output.parent().unwrap())
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                let name = name.to_string_lossy();
+                name.starts_with(&prefix) && name.ends_with(".tmp")
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+        fs::remove_file(input).unwrap();
+        fs::remove_file(output).unwrap();
+    }
+}
@@ -0,0 +1,201 @@
+use std::fmt;
+
+use hkdf::Hkdf;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
+
+use super::crypto_service::CryptoError;
+use super::key_resolver::{unwrap_key, wrap_key};
+
+/// Префикс открытого ключа получателя
+pub const RECIPIENT_PREFIX: &str = "x25519:";
+/// Префикс секретного ключа в файле identity
+const IDENTITY_PREFIX: &str = "X25519-SECRET-KEY:";
+
+const RECIPIENT_ID_LEN: usize = 8;
+/// Отпечаток получателя, эфемерный открытый ключ и завёрнутый ключ данных
+const STANZA_LEN: usize = RECIPIENT_ID_LEN + 32 + 40;
+
+fn decode_key(value: &str, prefix: &str) -> Result<[u8; 32], CryptoError> {
+    let hex_key = value
+        .trim()
+        .strip_prefix(prefix)
+        .ok_or(CryptoError::InvalidKey(format!(
+            "ожидается префикс {prefix}"
+        )))?;
+
+    hex::decode(hex_key)
+        .ok()
+        .and_then(|key| <[u8; 32]>::try_from(key).ok())
+        .ok_or(CryptoError::InvalidKey(
+            "ожидается 32 байта в шестнадцатеричном виде".into(),
+        ))
+}
+
+/// Отпечаток получателя, которому адресовано поле `TAG_RECIPIENT`
+pub fn stanza_recipient_id(stanza: &[u8]) -> &[u8] {
+    &stanza[..RECIPIENT_ID_LEN.min(stanza.len())]
+}
+
+/// Ключ обёртки из общего секрета X25519, привязанный к обоим открытым ключам
+fn wrapping_key(
+    shared: SharedSecret,
+    ephemeral: &PublicKey,
+    recipient: &PublicKey,
+) -> Result<[u8; 32], CryptoError> {
+    if !shared.was_contributory() {
+        return Err(CryptoError::InvalidKey(
+            "открытый ключ получателя имеет малый порядок".into(),
+        ));
+    }
+
+    let mut salt = ephemeral.as_bytes().to_vec();
+    salt.extend_from_slice(recipient.as_bytes());
+
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes())
+        .expand(b"crypto_files x25519", &mut key)
+        .map_err(|e| CryptoError::AesError(e.to_string()))?;
+    Ok(key)
+}
+
+/// Получатель: открытый ключ X25519, для которого заворачивается ключ данных
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recipient(PublicKey);
+
+impl Recipient {
+    pub fn parse(value: &str) -> Result<Self, CryptoError> {
+        decode_key(value, RECIPIENT_PREFIX).map(|key| Self(PublicKey::from(key)))
+    }
+
+    /// Отпечаток получателя, записываемый в начало его поля `TAG_RECIPIENT`
+    pub fn id(&self) -> [u8; RECIPIENT_ID_LEN] {
+        let digest = Sha256::new()
+            .chain_update(b"crypto_files recipient")
+            .chain_update(self.0.as_bytes())
+            .finalize();
+
+        let mut id = [0u8; RECIPIENT_ID_LEN];
+        id.copy_from_slice(&digest[..RECIPIENT_ID_LEN]);
+        id
+    }
+
+    /// Заворачивает ключ данных на эфемерном ключе X25519
+    pub fn wrap(&self, file_key: &[u8; 32]) -> Result<Vec<u8>, CryptoError> {
+        let mut ephemeral_bytes = [0u8; 32];
+        rand::rng().fill_bytes(&mut ephemeral_bytes);
+        let ephemeral_secret = StaticSecret::from(ephemeral_bytes);
+        let ephemeral = PublicKey::from(&ephemeral_secret);
+
+        let shared = ephemeral_secret.diffie_hellman(&self.0);
+        let key = wrapping_key(shared, &ephemeral, &self.0)?;
+
+        let mut stanza = self.id().to_vec();
+        stanza.extend_from_slice(ephemeral.as_bytes());
+        stanza.extend(wrap_key(&key, file_key)?);
+        Ok(stanza)
+    }
+}
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{RECIPIENT_PREFIX}{}", hex::encode(self.0.as_bytes()))
+    }
+}
+
+/// Секретный ключ X25519 получателя
+pub struct Identity(StaticSecret);
+
+impl Identity {
+    pub fn generate() -> Self {
+        let mut secret = [0u8; 32];
+        rand::rng().fill_bytes(&mut secret);
+        Self(StaticSecret::from(secret))
+    }
+
+    pub fn parse(value: &str) -> Result<Self, CryptoError> {
+        decode_key(value, IDENTITY_PREFIX).map(|key| Self(StaticSecret::from(key)))
+    }
+
+    /// Разбирает файл identity: пустые строки и комментарии `#` пропускаются
+    pub fn parse_file(content: &str) -> Result<Vec<Self>, CryptoError> {
+        content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(Self::parse)
+            .collect()
+    }
+
+    pub fn recipient(&self) -> Recipient {
+        Recipient(PublicKey::from(&self.0))
+    }
+
+    /// Секретный ключ в формате файла identity
+    pub fn encode(&self) -> String {
+        format!("{IDENTITY_PREFIX}{}", hex::encode(self.0.as_bytes()))
+    }
+
+    /// Содержимое файла identity с открытым ключом в комментарии
+    pub fn to_file(&self) -> String {
+        format!("# recipient: {}\n{}\n", self.recipient(), self.encode())
+    }
+
+    /// Разворачивает ключ данных из поля получателя, если оно адресовано
+    /// этому ключу
+    pub fn unwrap(&self, stanza: &[u8]) -> Result<Option<[u8; 32]>, CryptoError> {
+        if stanza.len() != STANZA_LEN {
+            return Err(CryptoError::InvalidHeader(
+                "Некорректная длина поля получателя".into(),
+            ));
+        }
+
+        let recipient = self.recipient();
+        if stanza_recipient_id(stanza) != recipient.id() {
+            return Ok(None);
+        }
+
+        let mut ephemeral = [0u8; 32];
+        ephemeral.copy_from_slice(&stanza[RECIPIENT_ID_LEN..RECIPIENT_ID_LEN + 32]);
+        let ephemeral = PublicKey::from(ephemeral);
+        let key = wrapping_key(self.0.diffie_hellman(&ephemeral), &ephemeral, &recipient.0)?;
+
+        unwrap_key(&key, &stanza[RECIPIENT_ID_LEN + 32..]).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap_unwrap() {
+        let identity = Identity::generate();
+        let other = Identity::generate();
+        let file_key = [5u8; 32];
+
+        let stanza = identity.recipient().wrap(&file_key).unwrap();
+
+        assert_eq!(identity.unwrap(&stanza).unwrap(), Some(file_key));
+        assert_eq!(other.unwrap(&stanza).unwrap(), None);
+    }
+
+    #[test]
+    fn test_identity_file() {
+        let identity = Identity::generate();
+
+        let parsed = Identity::parse_file(&identity.to_file()).unwrap();
+        let recipient = Recipient::parse(&identity.recipient().to_string()).unwrap();
+
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].recipient(), identity.recipient());
+        assert_eq!(recipient, identity.recipient());
+    }
+
+    #[test]
+    fn test_invalid_recipient() {
+        assert!(Recipient::parse("x25519:abcd").is_err());
+        assert!(Recipient::parse(&"00".repeat(32)).is_err());
+    }
+}
@@ -0,0 +1,136 @@
+use std::io::{BufWriter, Read, StdinLock, StdoutLock, Write};
+
+use crate::domain::services::FileService;
+
+use super::file_service::FsError;
+
+/// Путь, который означает чтение из stdin и запись в stdout
+pub const STDIO_PATH: &str = "-";
+
+/// Читает исходные данные из потока и пишет результат в другой поток.
+///
+/// Временного файла, замены и удаления нет: `commit` только сбрасывает
+/// вывод, а `revert` отбрасывает ещё не записанную часть. Уже выведенные
+/// данные отменить нельзя, об ошибке сообщает код выхода.
+pub struct StreamFileService<R, W: Write> {
+    chunk_size: usize,
+    reader: R,
+    writer: Option<BufWriter<W>>,
+}
+
+impl StreamFileService<StdinLock<'static>, StdoutLock<'static>> {
+    pub fn stdio(chunk_size: usize) -> Self {
+        Self::new(
+            chunk_size,
+            std::io::stdin().lock(),
+            std::io::stdout().lock(),
+        )
+    }
+}
+
+impl<R: Read, W: Write> StreamFileService<R, W> {
+    pub fn new(chunk_size: usize, reader: R, writer: W) -> Self {
+        Self {
+            chunk_size,
+            reader,
+            writer: Some(BufWriter::new(writer)),
+        }
+    }
+}
+
+impl<R: Read, W: Write> FileService for StreamFileService<R, W> {
+    type Error = FsError;
+
+    fn init_original(&mut self) -> Result<&mut Self, Self::Error> {
+        Ok(self)
+    }
+
+    fn make_temp(&mut self) -> Result<&mut Self, Self::Error> {
+        Ok(self)
+    }
+
+    fn commit(&mut self) -> Result<&mut Self, Self::Error> {
+        let writer = self.writer.take().ok_or(FsError::NoTempFile)?;
+        writer
+            .into_inner()
+            .map_err(|e| FsError::Io(e.into_error()))?;
+        Ok(self)
+    }
+
+    fn revert(&mut self) -> Result<&mut Self, Self::Error> {
+        let writer = self.writer.take().ok_or(FsError::NoTempFile)?;
+        let _ = writer.into_parts();
+        Ok(self)
+    }
+
+    /// Читает из потока, пока не наберётся `chunk_size` байт или поток
+    /// не закончится: из канала данные приходят частями произвольного размера
+    fn read_chunk_original(&mut self) -> Result<Option<Vec<u8>>, Self::Error> {
+        let buf = self.read_exact_original(self.chunk_size)?;
+        Ok((!buf.is_empty()).then_some(buf))
+    }
+
+    fn read_exact_original(&mut self, len: usize) -> Result<Vec<u8>, Self::Error> {
+        let mut buf = Vec::with_capacity(len);
+        (&mut self.reader)
+            .take(len as u64)
+            .read_to_end(&mut buf)
+            .map_err(FsError::Io)?;
+        Ok(buf)
+    }
+
+    fn write_chunk(&mut self, chunk: Vec<u8>) -> Result<(), Self::Error> {
+        let writer = self.writer.as_mut().ok_or(FsError::NoTempFile)?;
+        writer.write_all(&chunk).map_err(FsError::Io)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Проверяем, что данные читаются частями chunk_size и выводятся после commit
+    #[test]
+    fn test_stream() {
+        let mut output = Vec::new();
+        let mut service = StreamFileService::new(4, &b"abcdefghij"[..], &mut output);
+
+        let mut chunks = Vec::new();
+        while let Some(chunk) = service.read_chunk_original().unwrap() {
+            service.write_chunk(chunk.clone()).unwrap();
+            chunks.push(chunk);
+        }
+        service.commit().unwrap();
+        drop(service);
+
+        assert_eq!(
+            chunks,
+            vec![b"abcd".to_vec(), b"efgh".to_vec(), b"ij".to_vec()]
+        );
+        assert_eq!(output, b"abcdefghij");
+    }
+
+    //Проверяем, что точное чтение не зависит от chunk_size, а в конце потока возвращает остаток
+    #[test]
+    fn test_read_exact() {
+        let mut output = Vec::new();
+        let mut service = StreamFileService::new(2, &b"abcdefg"[..], &mut output);
+
+        assert_eq!(service.read_exact_original(5).unwrap(), b"abcde");
+        assert_eq!(service.read_chunk_original().unwrap(), Some(b"fg".to_vec()));
+        assert_eq!(service.read_exact_original(3).unwrap(), b"");
+    }
+
+    //Проверяем, что после revert невыведенные данные отбрасываются
+    #[test]
+    fn test_revert() {
+        let mut output = Vec::new();
+        let mut service = StreamFileService::new(4, &b"abcd"[..], &mut output);
+
+        service.write_chunk(b"partial".to_vec()).unwrap();
+        service.revert().unwrap();
+        drop(service);
+
+        assert!(output.is_empty());
+    }
+}
@@ -1,7 +1,32 @@
+use std::io::{IsTerminal, Write};
+
 use crate::domain::services::TerminalService;
 
 pub struct Terminal;
 
+/// Терминал для работы в канале: stdout занят данными, поэтому
+/// сообщения выводятся в stderr
+pub struct PipeTerminal;
+
+impl TerminalService for PipeTerminal {
+    fn print_msg(&self, msg: String) {
+        eprintln!("{msg}");
+    }
+    fn print_message_in_line(&self, msg: String) {
+        eprint!("\x1B[1A\x1B[2K");
+        eprintln!("{msg}");
+    }
+    fn print_error_msg(&self, msg: String) {
+        eprintln!("{msg}");
+    }
+    fn print_chunk(&self, value: Vec<u8>) {
+        Terminal.print_chunk(value);
+    }
+    fn ask(&self, _question: String) -> Option<String> {
+        None
+    }
+}
+
 impl TerminalService for Terminal {
     fn print_msg(&self, msg: String) {
         println!("{msg}");
@@ -13,8 +38,24 @@ impl TerminalService for Terminal {
     fn print_error_msg(&self, msg: String) {
         eprintln!("{msg}");
     }
+    /// Выводит байты без преобразования. Если читатель закрыл канал
+    /// (например, `read file | head`), остаток вывода отбрасывается
     fn print_chunk(&self, value: Vec<u8>) {
-        let text = String::from_utf8_lossy(&value);
-        print!("{text}");
+        let mut stdout = std::io::stdout().lock();
+        let _ = stdout.write_all(&value).and_then(|_| stdout.flush());
+    }
+    fn ask(&self, question: String) -> Option<String> {
+        if !std::io::stdin().is_terminal() {
+            return None;
+        }
+
+        print!("{question} ");
+        std::io::stdout().flush().ok()?;
+
+        let mut answer = String::new();
+        match std::io::stdin().read_line(&mut answer) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(answer.trim().to_string()),
+        }
     }
 }
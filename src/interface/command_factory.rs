@@ -1,21 +1,242 @@
-use crate::domain::models::Command;
+use crate::domain::models::{
+    AlreadyProcessed, CliOptions, Command, KeyFormat, PreserveOptions, ReadFormat, RecipientsAction,
+};
+
+const USAGE: &str = "Использование: <command> [-r] [--include <glob>]... [--exclude <glob>]... [--preserve <attrs>] [--passphrase] [--key-id <name>] [--recipient <pubkey>]... [--identity <path>] [--temp-dir <dir>] [--config <path>] [--profile <name>] <path>\n       read [--text|--hex] <path>\n       prepare|decrypt [--on-already skip|error|force] [-o <output> [-f]] <path>\n       decrypt|rekey [--force-legacy] <path>\n       rekey [--from-key-id <name>] [--to-key-id <name> | --to-passphrase] [--full] <path>\n       keygen [--symmetric [--name <name>] [--format hex|base64|raw] [--suite <suite>]] <path>\n       recipients add|remove|list [--recipient <pubkey>]... <path>\n       recover [--yes] [--exclude <glob>]... [<dir>]\n       config show";
 
 pub struct CommandFactory;
 
 impl CommandFactory {
-    pub fn from_args(args: &[String]) -> Result<Command, String> {
-        if args.len() != 3 {
-            return Err("Использование: <command> <path>".into());
+    pub fn from_args(args: &[String]) -> Result<(Command, CliOptions), String> {
+        let mut options = CliOptions::default();
+        let mut positional = Vec::new();
+
+        let mut args = args.iter().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--passphrase" => options.passphrase = true,
+                "--key-id" => {
+                    let name = args.next().ok_or("Флаг --key-id требует имя ключа")?;
+                    options.key_id = Some(name.clone());
+                }
+                "--recipient" => {
+                    let recipient = args
+                        .next()
+                        .ok_or("Флаг --recipient требует открытый ключ")?;
+                    options.recipients.push(recipient.clone());
+                }
+                "-r" | "--recursive" => options.recursive = true,
+                "-y" | "--yes" => options.assume_yes = true,
+                "-o" | "--output" => {
+                    let path = args.next().ok_or("Флаг --output требует путь к файлу")?;
+                    options.output = Some(path.clone());
+                }
+                "-f" | "--force" => options.force = true,
+                "--force-legacy" => options.force_legacy = true,
+                "--from-key-id" | "--to-key-id" => {
+                    let name = args.next().ok_or(format!("Флаг {arg} требует имя ключа"))?;
+                    if arg == "--from-key-id" {
+                        options.from_key_id = Some(name.clone());
+                    } else {
+                        options.to_key_id = Some(name.clone());
+                    }
+                }
+                "--to-passphrase" => options.to_passphrase = true,
+                "--full" => options.full_rekey = true,
+                "--on-already" => {
+                    let policy = args
+                        .next()
+                        .ok_or("Флаг --on-already требует skip, error или force")?;
+                    options.on_already = match policy.as_str() {
+                        "skip" => AlreadyProcessed::Skip,
+                        "error" => AlreadyProcessed::Error,
+                        "force" => AlreadyProcessed::Force,
+                        other => return Err(format!("Неизвестная политика --on-already: {other}")),
+                    };
+                }
+                "--text" | "--hex" => {
+                    if options.read_format != ReadFormat::Raw {
+                        return Err("Укажите только один из флагов --text и --hex".into());
+                    }
+                    options.read_format = match arg.as_str() {
+                        "--text" => ReadFormat::Text,
+                        _ => ReadFormat::Hex,
+                    };
+                }
+                "--include" | "--exclude" => {
+                    let pattern = args.next().ok_or(format!("Флаг {arg} требует шаблон"))?;
+                    glob::Pattern::new(pattern)
+                        .map_err(|e| format!("Некорректный шаблон {pattern}: {e}"))?;
+                    if arg == "--include" {
+                        options.include.push(pattern.clone());
+                    } else {
+                        options.exclude.push(pattern.clone());
+                    }
+                }
+                "--preserve" => {
+                    let list = args
+                        .next()
+                        .ok_or("Флаг --preserve требует список атрибутов")?;
+                    options.preserve = Self::preserve_options(list)?;
+                }
+                "--symmetric" => options.keygen.symmetric = true,
+                "--name" => {
+                    let name = args.next().ok_or("Флаг --name требует имя ключа")?;
+                    options.keygen.name = Some(name.clone());
+                }
+                "--format" => {
+                    let format = args
+                        .next()
+                        .ok_or("Флаг --format требует hex, base64 или raw")?;
+                    options.keygen.format = match format.as_str() {
+                        "hex" => KeyFormat::Hex,
+                        "base64" => KeyFormat::Base64,
+                        "raw" => KeyFormat::Raw,
+                        other => return Err(format!("Неизвестный формат ключа: {other}")),
+                    };
+                }
+                "--suite" => {
+                    let suite = args.next().ok_or("Флаг --suite требует набор алгоритмов")?;
+                    options.keygen.suite = Some(suite.clone());
+                }
+                "--temp-dir" => {
+                    let dir = args.next().ok_or("Флаг --temp-dir требует каталог")?;
+                    options.temp_dir = Some(dir.clone());
+                }
+                "--config" => {
+                    let path = args.next().ok_or("Флаг --config требует путь к файлу")?;
+                    options.config = Some(path.clone());
+                }
+                "--profile" => {
+                    let name = args.next().ok_or("Флаг --profile требует имя профиля")?;
+                    options.profile = Some(name.clone());
+                }
+                "--identity" => {
+                    let path = args.next().ok_or("Флаг --identity требует путь к файлу")?;
+                    options.identity = Some(path.clone());
+                }
+                flag if flag.starts_with("--") => {
+                    return Err(format!("Неизвестный флаг: {flag}"));
+                }
+                value => positional.push(value.to_string()),
+            }
+        }
+
+        if positional.first().map(String::as_str) == Some("recipients") {
+            return Self::recipients_command(&positional, options);
         }
 
-        let command_name = args[1].as_str();
-        let path = args[2].clone();
+        if positional == ["config", "show"] {
+            return Ok((Command::ShowConfig, options));
+        }
+
+        if positional.first().map(String::as_str) == Some("recover") && positional.len() <= 2 {
+            let dir = positional.get(1).cloned().unwrap_or(".".into());
+            return Ok((Command::Recover(dir), options));
+        }
 
-        match command_name {
-            "prepare" => Ok(Command::Prepare(path)),
-            "read" => Ok(Command::Read(path)),
-            "decrypt" => Ok(Command::Decrypt(path)),
-            other => Err(format!("Неизвестная команда: {other}")),
+        if positional.len() != 2 {
+            return Err(USAGE.into());
         }
+
+        let command_name = positional[0].as_str();
+        let path = positional[1].clone();
+
+        if options.output.is_some() && !matches!(command_name, "prepare" | "decrypt") {
+            return Err("Флаг --output поддерживают только prepare и decrypt".into());
+        }
+        if options.output.is_some() && path == "-" {
+            return Err(
+                "При чтении из stdin результат выводится в stdout, --output не нужен".into(),
+            );
+        }
+
+        if options.force_legacy && !matches!(command_name, "decrypt" | "rekey") {
+            return Err("Флаг --force-legacy поддерживают только decrypt и rekey".into());
+        }
+
+        Self::check_rekey_options(command_name, &options)?;
+
+        if options.read_format != ReadFormat::Raw && command_name != "read" {
+            return Err("Флаги --text и --hex поддерживает только read".into());
+        }
+
+        let command = match command_name {
+            "prepare" => Command::Prepare(path),
+            "read" => Command::Read(path),
+            "decrypt" => Command::Decrypt(path),
+            "rekey" => Command::Rekey(path),
+            "keygen" => Command::Keygen(std::mem::take(&mut options.keygen), path),
+            other => return Err(format!("Неизвестная команда: {other}")),
+        };
+        Ok((command, options))
+    }
+
+    /// Старый и новый ключ `rekey` задаются раздельно, а новый — только одним
+    /// способом, чтобы ни один флаг не игнорировался молча
+    fn check_rekey_options(command_name: &str, options: &CliOptions) -> Result<(), String> {
+        let rekey_flags = options.from_key_id.is_some()
+            || options.to_key_id.is_some()
+            || options.to_passphrase
+            || options.full_rekey;
+        if command_name != "rekey" {
+            return match rekey_flags {
+                true => Err(
+                    "Флаги --from-key-id, --to-key-id, --to-passphrase и --full поддерживает только rekey"
+                        .into(),
+                ),
+                false => Ok(()),
+            };
+        }
+
+        if options.key_id.is_some() {
+            return Err(
+                "Для rekey укажите старый ключ флагом --from-key-id, а новый — --to-key-id".into(),
+            );
+        }
+        if options.to_key_id.is_some() && options.to_passphrase {
+            return Err("Укажите только один из флагов --to-key-id и --to-passphrase".into());
+        }
+        Ok(())
+    }
+
+    /// Список через запятую: `mode`, `owner`, `timestamps`, `xattrs`, `all` или `none`
+    fn preserve_options(list: &str) -> Result<PreserveOptions, String> {
+        let mut preserve = PreserveOptions::none();
+        for name in list.split(',') {
+            match name.trim() {
+                "mode" => preserve.mode = true,
+                "owner" => preserve.owner = true,
+                "timestamps" => preserve.timestamps = true,
+                "xattrs" => preserve.xattrs = true,
+                "all" => preserve = PreserveOptions::default(),
+                "none" => {}
+                other => return Err(format!("Неизвестный атрибут файла: {other}")),
+            }
+        }
+        Ok(preserve)
+    }
+
+    /// `recipients add|remove|list <path>`. Получатели из `--recipient`
+    /// относятся к действию и не добавляются к получателям из настроек.
+    fn recipients_command(
+        positional: &[String],
+        mut options: CliOptions,
+    ) -> Result<(Command, CliOptions), String> {
+        let [_, action, path] = positional else {
+            return Err(USAGE.into());
+        };
+
+        let recipients = std::mem::take(&mut options.recipients);
+        let action = match action.as_str() {
+            "add" | "remove" if recipients.is_empty() => {
+                return Err("Укажите получателей флагом --recipient".into());
+            }
+            "add" => RecipientsAction::Add(recipients),
+            "remove" => RecipientsAction::Remove(recipients),
+            "list" => RecipientsAction::List,
+            other => return Err(format!("Неизвестное действие с получателями: {other}")),
+        };
+        Ok((Command::Recipients(action, path.clone()), options))
     }
 }
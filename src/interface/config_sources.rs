@@ -0,0 +1,368 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use config::{Config, ConfigError, Environment, File, FileFormat, Map, Source, Value, ValueKind};
+
+use crate::domain::models::{CliOptions, KdfSettings};
+
+use super::credentials_loader::CredentialsLoaderError;
+
+/// Имя файла настроек в каталогах поиска
+pub const CONFIG_FILE_NAME: &str = "credentials.toml";
+
+/// Каталог приложения в `$XDG_CONFIG_HOME` и `/etc`
+const APP_DIR: &str = "crypto_files";
+
+/// Префикс переменных окружения с настройками
+const ENV_PREFIX: &str = "CRYPTO";
+
+/// Источник, которым `config` помечает значения из переменных окружения
+const ENV_ORIGIN: &str = "the environment";
+
+/// Настройки, значения которых не выводятся
+const SECRET_KEYS: [&str; 4] = ["key", "keys", "passphrase", "new_passphrase"];
+
+/// Файлы настроек в порядке поиска: текущий каталог,
+/// `$XDG_CONFIG_HOME/crypto_files/` (по умолчанию `~/.config/crypto_files/`)
+/// и `/etc/crypto_files/`
+fn candidates(xdg_config_home: Option<OsString>, home: Option<OsString>) -> Vec<PathBuf> {
+    let config_home = xdg_config_home
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| home.map(|home| PathBuf::from(home).join(".config")));
+
+    let mut paths = vec![PathBuf::from(CONFIG_FILE_NAME)];
+    if let Some(dir) = config_home {
+        paths.push(dir.join(APP_DIR).join(CONFIG_FILE_NAME));
+    }
+    paths.push(Path::new("/etc").join(APP_DIR).join(CONFIG_FILE_NAME));
+    paths
+}
+
+/// Значения файла настроек или секции профиля, помеченные источником.
+/// `config` записывает в источник путь относительно текущего каталога,
+/// поэтому значения помечаются путём в том виде, в котором файл был найден.
+#[derive(Debug, Clone)]
+struct LabeledSource(Map<String, Value>);
+
+impl LabeledSource {
+    fn new(values: Map<String, Value>, origin: String) -> Self {
+        Self(
+            values
+                .into_iter()
+                .map(|(key, value)| (key, relabel(value, &origin)))
+                .collect(),
+        )
+    }
+}
+
+impl Source for LabeledSource {
+    fn clone_into_box(&self) -> Box<dyn Source + Send + Sync> {
+        Box::new(self.clone())
+    }
+
+    fn collect(&self) -> Result<Map<String, Value>, ConfigError> {
+        Ok(self.0.clone())
+    }
+}
+
+/// Помечает значение и вложенные значения источником `origin`
+fn relabel(value: Value, origin: &String) -> Value {
+    let kind = match value.kind {
+        ValueKind::Table(table) => ValueKind::Table(
+            table
+                .into_iter()
+                .map(|(key, value)| (key, relabel(value, origin)))
+                .collect(),
+        ),
+        ValueKind::Array(items) => {
+            ValueKind::Array(items.into_iter().map(|v| relabel(v, origin)).collect())
+        }
+        kind => kind,
+    };
+    Value::new(Some(origin), kind)
+}
+
+/// Откуда берутся настройки: файл из `--config` или найденный поиском
+/// и профиль из `--profile` или `CRYPTO_PROFILE`.
+///
+/// Значения перекрываются в порядке: общие значения файла, секция профиля,
+/// переменные окружения `CRYPTO_*`.
+pub struct ConfigSources {
+    path: Option<PathBuf>,
+    profile: Option<String>,
+}
+
+impl ConfigSources {
+    pub fn discover(options: &CliOptions) -> Result<Self, CredentialsLoaderError> {
+        let path = match &options.config {
+            Some(path) if Path::new(path).is_file() => Some(PathBuf::from(path)),
+            Some(path) => return Err(CredentialsLoaderError::ConfigNotFound(path.clone())),
+            None => candidates(
+                std::env::var_os("XDG_CONFIG_HOME"),
+                std::env::var_os("HOME"),
+            )
+            .into_iter()
+            .find(|path| path.is_file()),
+        };
+
+        let profile = options.profile.clone().or_else(|| {
+            std::env::var(format!("{ENV_PREFIX}_PROFILE"))
+                .ok()
+                .filter(|name| !name.is_empty())
+        });
+
+        Ok(Self { path, profile })
+    }
+
+    pub fn build(&self) -> Result<Config, CredentialsLoaderError> {
+        let mut builder = Config::builder();
+        match (&self.path, &self.profile) {
+            (Some(path), profile) => {
+                let mut values = Config::builder()
+                    .add_source(File::from(path.as_path()).format(FileFormat::Toml))
+                    .build()
+                    .and_then(|file| file.collect())
+                    .map_err(CredentialsLoaderError::LoadingError)?;
+                let profiles = values.remove("profile");
+                builder =
+                    builder.add_source(LabeledSource::new(values, path.display().to_string()));
+
+                if let Some(name) = profile {
+                    let values = profiles
+                        .and_then(|profiles| profiles.into_table().ok())
+                        .and_then(|mut profiles| profiles.remove(name))
+                        .ok_or(CredentialsLoaderError::UnknownProfile(name.clone()))?
+                        .into_table()
+                        .map_err(CredentialsLoaderError::LoadingError)?;
+                    let origin = format!("{} [profile.{name}]", path.display());
+                    builder = builder.add_source(LabeledSource::new(values, origin));
+                }
+            }
+            (None, Some(name)) => return Err(CredentialsLoaderError::UnknownProfile(name.clone())),
+            (None, None) => {}
+        }
+
+        builder
+            .add_source(Environment::with_prefix(ENV_PREFIX))
+            .build()
+            .map_err(CredentialsLoaderError::LoadingError)
+    }
+
+    /// Действующие настройки по строке на значение с указанием источника.
+    /// Ключи и пароль скрываются.
+    pub fn describe(&self, options: &CliOptions) -> Result<Vec<String>, CredentialsLoaderError> {
+        let config = self.build()?;
+        let root = config
+            .collect()
+            .map_err(CredentialsLoaderError::LoadingError)?;
+
+        let mut values = BTreeMap::new();
+        for (key, value) in root {
+            // `profile` из окружения — имя профиля, а не настройка
+            if key != "profile" {
+                flatten(key, value, &mut values);
+            }
+        }
+
+        let kdf = KdfSettings::default();
+        let defaults = [
+            ("kdf.memory_kib", kdf.memory_kib),
+            ("kdf.iterations", kdf.iterations),
+            ("kdf.parallelism", kdf.parallelism),
+        ];
+        for (key, value) in defaults {
+            values
+                .entry(key.into())
+                .or_insert_with(|| (value.to_string(), "по умолчанию".into()));
+        }
+
+        let flags = [
+            ("default_key", &options.key_id, "--key-id"),
+            ("identity", &options.identity, "--identity"),
+            ("temp_dir", &options.temp_dir, "--temp-dir"),
+        ];
+        for (key, value, flag) in flags {
+            if let Some(value) = value {
+                values.insert(key.into(), (value.clone(), format!("флаг {flag}")));
+            }
+        }
+        let configured = values
+            .keys()
+            .filter(|key| key.starts_with("recipients["))
+            .count();
+        for (i, recipient) in options.recipients.iter().enumerate() {
+            values.insert(
+                format!("recipients[{}]", configured + i),
+                (recipient.clone(), "флаг --recipient".into()),
+            );
+        }
+
+        let mut lines = vec![match &self.path {
+            Some(path) => format!("Файл настроек: {}", path.display()),
+            None => "Файл настроек не найден".to_string(),
+        }];
+        if let Some(name) = &self.profile {
+            lines.push(format!("Профиль: {name}"));
+        }
+        lines.extend(
+            values
+                .into_iter()
+                .map(|(key, (value, source))| format!("{key} = {value} ({source})")),
+        );
+        Ok(lines)
+    }
+}
+
+/// Раскладывает таблицы и массивы в ключи вида `kdf.iterations` и `recipients[0]`
+fn flatten(key: String, value: Value, out: &mut BTreeMap<String, (String, String)>) {
+    let source = match value.origin() {
+        Some(ENV_ORIGIN) => format!("переменная окружения {ENV_PREFIX}_{}", key.to_uppercase()),
+        Some(origin) => format!("файл {origin}"),
+        None => "неизвестно".to_string(),
+    };
+    let is_secret = SECRET_KEYS.contains(&key.split(['.', '[']).next().unwrap_or_default());
+
+    match value.kind {
+        ValueKind::Table(table) => {
+            for (name, value) in table {
+                flatten(format!("{key}.{name}"), value, out);
+            }
+        }
+        ValueKind::Array(items) => {
+            for (i, value) in items.into_iter().enumerate() {
+                flatten(format!("{key}[{i}]"), value, out);
+            }
+        }
+        _ if is_secret => {
+            out.insert(key, ("<скрыт>".into(), source));
+        }
+        kind => {
+            out.insert(key, (kind.to_string(), source));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::models::Credentials;
+    use std::fs;
+
+    const KEY: &str = "1111111111111111111111111111111111111111111111111111111111111111";
+
+    fn write_config(content: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("crypto_files_config_{}.toml", uuid::Uuid::new_v4()));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn sources(path: &Path, profile: Option<&str>) -> ConfigSources {
+        ConfigSources {
+            path: Some(path.to_path_buf()),
+            profile: profile.map(String::from),
+        }
+    }
+
+    //Проверяем порядок поиска файла настроек и запасной каталог без XDG_CONFIG_HOME
+    #[test]
+    fn test_candidates() {
+        let paths = candidates(Some("/xdg".into()), Some("/home/u".into()));
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("credentials.toml"),
+                PathBuf::from("/xdg/crypto_files/credentials.toml"),
+                PathBuf::from("/etc/crypto_files/credentials.toml"),
+            ]
+        );
+
+        let paths = candidates(Some("".into()), Some("/home/u".into()));
+        assert_eq!(
+            paths[1],
+            PathBuf::from("/home/u/.config/crypto_files/credentials.toml")
+        );
+    }
+
+    //Проверяем, что секция профиля перекрывает общие значения и дополняет связку
+    #[test]
+    fn test_profile() {
+        let path = write_config(&format!(
+            "chunk_size = 4096\ndefault_key = \"a\"\n[keys]\na = \"{KEY}\"\n\
+             [profile.work]\ndefault_key = \"b\"\ntemp_dir = \"/tmp\"\n[profile.work.keys]\nb = \"{KEY}\"\n"
+        ));
+
+        let config = sources(&path, Some("work")).build().unwrap();
+        let creds = config.try_deserialize::<Credentials>().unwrap();
+        assert_eq!(creds.default_key.as_deref(), Some("b"));
+        assert_eq!(creds.temp_dir.as_deref(), Some("/tmp"));
+        assert_eq!(creds.keys.len(), 2);
+
+        let config = sources(&path, None).build().unwrap();
+        let creds = config.try_deserialize::<Credentials>().unwrap();
+        assert_eq!(creds.default_key.as_deref(), Some("a"));
+
+        let result = sources(&path, Some("home")).build();
+        assert!(
+            matches!(result, Err(CredentialsLoaderError::UnknownProfile(name)) if name == "home")
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    //Проверяем, что ключи скрыты, а у каждого значения указан источник
+    #[test]
+    fn test_describe() {
+        let path = write_config(&format!(
+            "chunk_size = \"64KiB\"\nkey = \"{KEY}\"\n[profile.work]\nrecipients = [\"x25519:aa\"]\n"
+        ));
+        let options = CliOptions {
+            key_id: Some("2026".into()),
+            recipients: vec!["x25519:bb".into()],
+            ..CliOptions::default()
+        };
+
+        let lines = sources(&path, Some("work")).describe(&options).unwrap();
+        let file = path.display();
+
+        assert!(lines.contains(&format!("Файл настроек: {file}")));
+        assert!(lines.contains(&"Профиль: work".to_string()));
+        assert!(lines.contains(&format!("chunk_size = 64KiB (файл {file})")));
+        assert!(lines.contains(&format!("key = <скрыт> (файл {file})")));
+        assert!(lines.contains(&format!(
+            "recipients[0] = x25519:aa (файл {file} [profile.work])"
+        )));
+        assert!(lines.contains(&"recipients[1] = x25519:bb (флаг --recipient)".to_string()));
+        assert!(lines.contains(&"default_key = 2026 (флаг --key-id)".to_string()));
+        assert!(lines.contains(&"kdf.iterations = 3 (по умолчанию)".to_string()));
+        assert!(!lines.iter().any(|line| line.contains(KEY)));
+        fs::remove_file(path).unwrap();
+    }
+
+    //Проверяем, что при частично заданной секции [kdf] остальные параметры
+    //берутся по умолчанию и при загрузке, и при выводе настроек
+    #[test]
+    fn test_partial_kdf() {
+        let path = write_config(&format!(
+            "chunk_size = 4096\nkey = \"{KEY}\"\n[kdf]\nmemory_kib = 1024\n"
+        ));
+
+        let creds = sources(&path, None)
+            .build()
+            .unwrap()
+            .try_deserialize::<Credentials>()
+            .unwrap();
+        assert_eq!(creds.kdf.memory_kib, 1024);
+        assert_eq!(creds.kdf.iterations, 3);
+
+        let lines = sources(&path, None)
+            .describe(&CliOptions::default())
+            .unwrap();
+        let file = path.display();
+        assert!(lines.contains(&format!("kdf.memory_kib = 1024 (файл {file})")));
+        assert!(lines.contains(&"kdf.iterations = 3 (по умолчанию)".to_string()));
+        assert!(lines.contains(&"kdf.parallelism = 1 (по умолчанию)".to_string()));
+        fs::remove_file(path).unwrap();
+    }
+}
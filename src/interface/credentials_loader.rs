@@ -1,13 +1,38 @@
-use crate::domain::models::Credentials;
+use crate::domain::models::{CliOptions, Credentials};
 use crate::domain::services::ConsoleError;
-use config::{Config, ConfigError};
+use crate::infrastructure::header::CipherSuite;
+use crate::infrastructure::keyring::Keyring;
+use crate::infrastructure::recipients::{Identity, Recipient};
+use config::ConfigError;
+use std::cmp::Ordering;
 
-const MIN_KEY_LEN: usize = 32;
+use super::config_sources::ConfigSources;
 
+/// Набор алгоритмов новых файлов, по нему проверяется длина ключей
+const KEY_SUITE: CipherSuite = CipherSuite::Aes256GcmStream;
+
+/// Наибольший размер блока: блок целиком находится в памяти
+const MAX_CHUNK_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Debug)]
 pub enum CredentialsLoaderError {
     LoadingError(ConfigError),
     DeserializeError(ConfigError),
-    InvalidKeyLength(usize),
+    ConfigNotFound(String),
+    UnknownProfile(String),
+    MissingChunkSize,
+    InvalidChunkSize(String),
+    ZeroChunkSize,
+    ChunkSizeTooLarge(String),
+    KeyTooShort(String, usize),
+    KeyTooLong(String, usize),
+    MissingKey,
+    PromptError(std::io::Error),
+    EmptyPassphrase,
+    PassphraseMismatch,
+    UnknownKeyName(String),
+    InvalidRecipient(String, String),
+    IdentityError(String, String),
 }
 
 impl ConsoleError for CredentialsLoaderError {
@@ -16,13 +41,55 @@ impl ConsoleError for CredentialsLoaderError {
             CredentialsLoaderError::LoadingError(e) => format!(
                 "Ошибка загрузки конфигурации {e}, убедитесь что файл credentials.toml или переменные окружения CRYPTO_CHUNK_SIZE и CRYPTO_KEY существую",
             ),
+            CredentialsLoaderError::ConfigNotFound(path) => {
+                format!("Файл настроек {path} не найден")
+            }
+            CredentialsLoaderError::UnknownProfile(name) => {
+                format!("Профиль {name} отсутствует в файле настроек")
+            }
             CredentialsLoaderError::DeserializeError(e) => {
                 format!(
                     "Не удалось получить настройки: {e}. Убедитесь что файл credentials.toml или существую переменные окружения CRYPTO_CHUNK_SIZE и CRYPTO_KEY существую"
                 )
             }
-            CredentialsLoaderError::InvalidKeyLength(v) => {
-                format!("Недостаточная длина ключа KEY. Нужно 32 байта, имеется {v}.")
+            CredentialsLoaderError::MissingChunkSize => {
+                "Не задан chunk_size: укажите его в credentials.toml или в CRYPTO_CHUNK_SIZE"
+                    .to_string()
+            }
+            CredentialsLoaderError::InvalidChunkSize(v) => format!(
+                "Некорректный chunk_size {v}: ожидается число байт, например 4096 или 64KiB"
+            ),
+            CredentialsLoaderError::ZeroChunkSize => {
+                "chunk_size должен быть больше нуля".to_string()
+            }
+            CredentialsLoaderError::ChunkSizeTooLarge(v) => format!(
+                "Слишком большой chunk_size {v}: допускается не больше {} MiB",
+                MAX_CHUNK_SIZE >> 20
+            ),
+            CredentialsLoaderError::KeyTooShort(name, len) => format!(
+                "Недостаточная длина ключа {name}. Нужно {} байта, имеется {len}.",
+                KEY_SUITE.key_len()
+            ),
+            CredentialsLoaderError::KeyTooLong(name, len) => format!(
+                "Слишком длинный ключ {name}. Нужно {} байта, имеется {len}.",
+                KEY_SUITE.key_len()
+            ),
+            CredentialsLoaderError::MissingKey => {
+                "Не задан ключ: укажите KEY, CRYPTO_PASSPHRASE, флаг --passphrase, --recipient или --identity".to_string()
+            }
+            CredentialsLoaderError::PromptError(e) => {
+                format!("Не удалось прочитать пароль из терминала: {e}")
+            }
+            CredentialsLoaderError::EmptyPassphrase => "Пароль не может быть пустым".to_string(),
+            CredentialsLoaderError::PassphraseMismatch => "Пароли не совпадают".to_string(),
+            CredentialsLoaderError::UnknownKeyName(name) => {
+                format!("Ключ {name} отсутствует в связке: укажите имя из keys или key")
+            }
+            CredentialsLoaderError::InvalidRecipient(recipient, e) => {
+                format!("Некорректный получатель {recipient}: {e}")
+            }
+            CredentialsLoaderError::IdentityError(path, e) => {
+                format!("Не удалось прочитать identity {path}: {e}")
             }
         }
     }
@@ -31,23 +98,303 @@ impl ConsoleError for CredentialsLoaderError {
 pub struct CredentialsLoader;
 
 impl CredentialsLoader {
-    pub fn try_load() -> Result<Credentials, CredentialsLoaderError> {
-        let config = Config::builder()
-            .add_source(config::File::with_name("credentials").required(false))
-            .add_source(config::Environment::with_prefix("CRYPTO"))
-            .build()
-            .map_err(CredentialsLoaderError::LoadingError)?;
-
-        let creds = config
+    /// Загружает настройки из файла, найденного `ConfigSources`, секции профиля
+    /// и переменных окружения. Если задан флаг `--passphrase`, а переменная
+    /// `CRYPTO_PASSPHRASE` отсутствует, пароль запрашивается в терминале без эха;
+    /// `confirm` требует ввести его дважды. Флаг `--key-id` заменяет `default_key`,
+    /// `--recipient` дополняет `recipients`, `--identity` заменяет `identity`,
+    /// `--temp-dir` заменяет `temp_dir`.
+    ///
+    /// Для `rekey` пароль из `--passphrase` — старый пароль, поэтому он
+    /// вводится один раз.
+    ///
+    /// Размер блока может быть задан с единицами (`64KiB`, `1MiB`), а длина
+    /// ключей должна точно совпадать с длиной ключа набора алгоритмов.
+    pub fn try_load(
+        options: &CliOptions,
+        confirm: bool,
+    ) -> Result<Credentials, CredentialsLoaderError> {
+        let config = ConfigSources::discover(options)?.build()?;
+
+        let chunk_size = match config.get_string("chunk_size") {
+            Ok(value) => Self::parse_chunk_size(&value)?,
+            Err(ConfigError::NotFound(_)) => return Err(CredentialsLoaderError::MissingChunkSize),
+            Err(e) => return Err(CredentialsLoaderError::DeserializeError(e)),
+        };
+
+        let mut creds = config
             .try_deserialize::<Credentials>()
             .map_err(CredentialsLoaderError::DeserializeError)?;
+        creds.chunk_size = chunk_size;
+
+        if options.passphrase && creds.passphrase.is_none() {
+            creds.passphrase = Some(Self::prompt_passphrase(confirm)?);
+        }
+
+        if let Some(key_id) = &options.key_id {
+            creds.default_key = Some(key_id.clone());
+        }
 
-        let creds_key_len = creds.key.len();
+        creds.recipients.extend(options.recipients.iter().cloned());
+        for recipient in &creds.recipients {
+            Recipient::parse(recipient).map_err(|e| {
+                CredentialsLoaderError::InvalidRecipient(recipient.clone(), e.consol_log())
+            })?;
+        }
+
+        if let Some(path) = &options.identity {
+            creds.identity = Some(path.clone());
+        }
+        if let Some(dir) = &options.temp_dir {
+            creds.temp_dir = Some(dir.clone());
+        }
+
+        if let Some(path) = &creds.identity {
+            creds.identities = Self::load_identities(path)?;
+        }
 
-        if creds_key_len < MIN_KEY_LEN {
-            return Err(CredentialsLoaderError::InvalidKeyLength(creds_key_len));
+        if let Some(passphrase) = &creds.passphrase {
+            if passphrase.is_empty() {
+                return Err(CredentialsLoaderError::EmptyPassphrase);
+            }
+        } else if creds.key.is_none()
+            && creds.keys.is_empty()
+            && creds.recipients.is_empty()
+            && creds.identities.is_empty()
+        {
+            return Err(CredentialsLoaderError::MissingKey);
         }
 
+        Self::validate_default_key(&creds)?;
+        Self::validate_keys(&creds)?;
+
         Ok(creds)
     }
+
+    /// Настройки старого и нового ключа для `rekey`. Старый ключ выбирается
+    /// по заголовку файла: пароль из `--passphrase`, ключ связки по отпечатку,
+    /// а для файлов без отпечатка — ключ `--from-key-id` или ключ по умолчанию.
+    /// Новый ключ — ключ связки `--to-key-id` (по умолчанию `default_key`) или,
+    /// с флагом `--to-passphrase`, пароль из `CRYPTO_NEW_PASSPHRASE`, который
+    /// иначе запрашивается дважды. Старый пароль для нового ключа не используется.
+    pub fn try_load_rekey(
+        options: &CliOptions,
+    ) -> Result<(Credentials, Credentials), CredentialsLoaderError> {
+        let mut source = Self::try_load(options, false)?;
+
+        let mut target = source.clone();
+        target.passphrase = None;
+        if let Some(name) = &options.to_key_id {
+            target.default_key = Some(name.clone());
+        }
+        if options.to_passphrase {
+            let passphrase = match target.new_passphrase.take() {
+                Some(passphrase) => passphrase,
+                None => Self::prompt_new_passphrase()?,
+            };
+            if passphrase.is_empty() {
+                return Err(CredentialsLoaderError::EmptyPassphrase);
+            }
+            target.passphrase = Some(passphrase);
+        }
+        Self::validate_default_key(&target)?;
+
+        if let Some(name) = &options.from_key_id {
+            source.default_key = Some(name.clone());
+            Self::validate_default_key(&source)?;
+        }
+        Ok((source, target))
+    }
+
+    /// Разбирает размер блока: число байт с необязательной единицей
+    /// `B`, `KB`, `MB`, `GB` (степени 1000) или `KiB`, `MiB`, `GiB` (степени 1024)
+    fn parse_chunk_size(value: &str) -> Result<usize, CredentialsLoaderError> {
+        let value = value.trim();
+        let digits_len = value
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(value.len());
+        let (number, unit) = value.split_at(digits_len);
+
+        let multiplier: usize = match unit.trim_start().to_ascii_lowercase().as_str() {
+            "" | "b" => 1,
+            "kb" => 1000,
+            "kib" => 1 << 10,
+            "mb" => 1000 * 1000,
+            "mib" => 1 << 20,
+            "gb" => 1000 * 1000 * 1000,
+            "gib" => 1 << 30,
+            _ => return Err(CredentialsLoaderError::InvalidChunkSize(value.into())),
+        };
+        if number.is_empty() {
+            return Err(CredentialsLoaderError::InvalidChunkSize(value.into()));
+        }
+
+        let size = number
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| n.checked_mul(multiplier));
+        match size {
+            Some(0) => Err(CredentialsLoaderError::ZeroChunkSize),
+            Some(size) if size <= MAX_CHUNK_SIZE => Ok(size),
+            _ => Err(CredentialsLoaderError::ChunkSizeTooLarge(value.into())),
+        }
+    }
+
+    /// Ключ по умолчанию выбирается по имени из связки, куда входит и `key`
+    fn validate_default_key(creds: &Credentials) -> Result<(), CredentialsLoaderError> {
+        match &creds.default_key {
+            Some(name) if !Keyring::names(creds).any(|n| n == name) => {
+                Err(CredentialsLoaderError::UnknownKeyName(name.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Ключи из `key` и `keys` должны быть ровно той длины, которую требует
+    /// набор алгоритмов: лишние байты иначе молча отбрасывались бы
+    fn validate_keys(creds: &Credentials) -> Result<(), CredentialsLoaderError> {
+        let named_keys = creds
+            .key
+            .iter()
+            .map(|key| ("key", key))
+            .chain(creds.keys.iter().map(|(name, key)| (name.as_str(), key)));
+
+        for (name, key) in named_keys {
+            match key.len().cmp(&KEY_SUITE.key_len()) {
+                Ordering::Less => {
+                    return Err(CredentialsLoaderError::KeyTooShort(name.into(), key.len()));
+                }
+                Ordering::Greater => {
+                    return Err(CredentialsLoaderError::KeyTooLong(name.into(), key.len()));
+                }
+                Ordering::Equal => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn load_identities(path: &str) -> Result<Vec<String>, CredentialsLoaderError> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| CredentialsLoaderError::IdentityError(path.into(), e.to_string()))?;
+
+        let identities = Identity::parse_file(&content)
+            .map_err(|e| CredentialsLoaderError::IdentityError(path.into(), e.consol_log()))?;
+        Ok(identities.iter().map(Identity::encode).collect())
+    }
+
+    fn prompt_passphrase(confirm: bool) -> Result<String, CredentialsLoaderError> {
+        let passphrase =
+            rpassword::prompt_password("Пароль: ").map_err(CredentialsLoaderError::PromptError)?;
+
+        if confirm {
+            let repeated = rpassword::prompt_password("Повторите пароль: ")
+                .map_err(CredentialsLoaderError::PromptError)?;
+            if repeated != passphrase {
+                return Err(CredentialsLoaderError::PassphraseMismatch);
+            }
+        }
+        Ok(passphrase)
+    }
+
+    fn prompt_new_passphrase() -> Result<String, CredentialsLoaderError> {
+        let passphrase = rpassword::prompt_password("Новый пароль: ")
+            .map_err(CredentialsLoaderError::PromptError)?;
+        let repeated = rpassword::prompt_password("Повторите новый пароль: ")
+            .map_err(CredentialsLoaderError::PromptError)?;
+        if repeated != passphrase {
+            return Err(CredentialsLoaderError::PassphraseMismatch);
+        }
+        Ok(passphrase)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    //Проверяем, что размер блока принимается в байтах и с единицами
+    #[test]
+    fn test_parse_chunk_size() {
+        let cases = [
+            ("4096", 4096),
+            ("1", 1),
+            ("64KiB", 64 * 1024),
+            ("64 kib", 64 * 1024),
+            ("2MB", 2_000_000),
+            ("1MiB", 1 << 20),
+            ("512B", 512),
+        ];
+        for (value, expected) in cases {
+            assert!(
+                matches!(CredentialsLoader::parse_chunk_size(value), Ok(size) if size == expected),
+                "{value}"
+            );
+        }
+    }
+
+    //Проверяем, что нулевой, огромный и нечисловой размер блока отклоняются
+    #[test]
+    fn test_invalid_chunk_size() {
+        assert!(matches!(
+            CredentialsLoader::parse_chunk_size("0KiB"),
+            Err(CredentialsLoaderError::ZeroChunkSize)
+        ));
+        for value in ["65MiB", "1GiB", "99999999999999999999999"] {
+            assert!(matches!(
+                CredentialsLoader::parse_chunk_size(value),
+                Err(CredentialsLoaderError::ChunkSizeTooLarge(_))
+            ));
+        }
+        for value in ["", "-1", "KiB", "64 KiBs", "1.5MiB"] {
+            assert!(matches!(
+                CredentialsLoader::parse_chunk_size(value),
+                Err(CredentialsLoaderError::InvalidChunkSize(_))
+            ));
+        }
+    }
+
+    //Проверяем, что ключ принимается только точной длины, а не обрезается
+    #[test]
+    fn test_key_length() {
+        let keys = |len| Credentials {
+            keys: BTreeMap::from([("2026".to_string(), vec![1; len])]),
+            ..Default::default()
+        };
+
+        assert!(CredentialsLoader::validate_keys(&keys(32)).is_ok());
+        assert!(matches!(
+            CredentialsLoader::validate_keys(&keys(31)),
+            Err(CredentialsLoaderError::KeyTooShort(name, 31)) if name == "2026"
+        ));
+        assert!(matches!(
+            CredentialsLoader::validate_keys(&keys(33)),
+            Err(CredentialsLoaderError::KeyTooLong(name, 33)) if name == "2026"
+        ));
+    }
+
+    //Проверяем, что ключ из корня настроек выбирается по имени key,
+    //а неизвестное имя отклоняется
+    #[test]
+    fn test_default_key_name() {
+        let mut creds = Credentials {
+            key: Some(vec![1; 32]),
+            keys: BTreeMap::from([("2026".to_string(), vec![2; 32])]),
+            ..Default::default()
+        };
+
+        for name in ["key", "2026"] {
+            creds.default_key = Some(name.into());
+            assert!(
+                CredentialsLoader::validate_default_key(&creds).is_ok(),
+                "{name}"
+            );
+        }
+
+        creds.default_key = Some("2025".into());
+        assert!(matches!(
+            CredentialsLoader::validate_default_key(&creds),
+            Err(CredentialsLoaderError::UnknownKeyName(name)) if name == "2025"
+        ));
+    }
 }
@@ -1,46 +1,598 @@
-use std::path::PathBuf;
+use std::cell::Cell;
+use std::path::{Path, PathBuf};
 
-use crate::domain::models::{Command, CommandError, Credentials};
-use crate::domain::services::BaseActions;
+use crate::domain::models::{
+    AlreadyProcessed, CancelToken, CliOptions, Command, CommandError, Credentials, KeyFormat,
+    KeygenOptions, RecipientsAction, RecoverAction, TempStatus,
+};
+use crate::domain::services::{BaseActions, ConsoleError, FileService, TerminalService};
 
 use crate::application::decrypt_use_case::DecryptUseCase;
+use crate::application::inspect_temp_use_case::InspectTempUseCase;
+use crate::application::list_recipients_use_case::ListRecipientsUseCase;
 use crate::application::prepare_use_case::PrepareUseCase;
 use crate::application::read_use_case::ReadUseCase;
-use crate::infrastructure::crypto_service::{AesCtrCryptoService, CryptoError};
-use crate::infrastructure::file_service::{FsError, LocalFileService};
-use crate::infrastructure::terminal_service::Terminal;
+use crate::application::rekey_use_case::RekeyUseCase;
+use crate::application::rewrite_header_use_case::RewriteHeaderUseCase;
+use crate::infrastructure::auto_crypto_service::AutoCryptoService;
+use crate::infrastructure::crypto_service::CryptoError;
+use crate::infrastructure::file_service::{
+    FsError, LocalFileService, is_volatile_dir, replace_secret_file, same_filesystem,
+    write_secret_file,
+};
+use crate::infrastructure::header::CipherSuite;
+use crate::infrastructure::header_rewriter::{HeaderEdit, HeaderRewriter};
+use crate::infrastructure::key_file::{
+    add_to_keyring, encode_key, generate_key, new_credentials, validate_name,
+};
+use crate::infrastructure::key_resolver::KeyResolver;
+use crate::infrastructure::orphan_temp::OrphanTemp;
+use crate::infrastructure::output_file_service::OutputFileService;
+use crate::infrastructure::recipients::Identity;
+use crate::infrastructure::stream_file_service::{STDIO_PATH, StreamFileService};
+use crate::infrastructure::terminal_service::{PipeTerminal, Terminal};
+
+use super::config_sources::ConfigSources;
+use super::file_walker::FileWalker;
+
+type ExecutorError = CommandError<FsError, CryptoError>;
 
 pub struct CommandExecutor {
     config: Credentials,
+    /// Настройки нового ключа для `rekey`
+    rekey_target: Option<Credentials>,
+    options: CliOptions,
+    cancel: CancelToken,
+    /// Предупреждение о временных файлах на постоянном носителе уже выведено
+    temp_warned: Cell<bool>,
 }
 
 impl CommandExecutor {
-    pub fn new(config: Credentials) -> Self {
-        Self { config }
+    pub fn new(config: Credentials, options: CliOptions) -> Self {
+        Self {
+            config,
+            rekey_target: None,
+            options,
+            cancel: CancelToken::default(),
+            temp_warned: Cell::new(false),
+        }
+    }
+
+    /// Настройки нового ключа для `rekey`; без них файлы перешифровываются
+    /// ключом по умолчанию из основных настроек
+    pub fn with_rekey_target(mut self, target: Credentials) -> Self {
+        self.rekey_target = Some(target);
+        self
+    }
+
+    /// Токен отмены, который передаётся всем операциям над файлами
+    pub fn with_cancel(mut self, cancel: CancelToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Файл, результат обработки которого пишется через временный файл
+    fn file_service(&self, path: impl Into<PathBuf>) -> LocalFileService {
+        LocalFileService::new(self.config.chunk_size, path)
+            .with_preserve(self.options.preserve)
+            .with_temp_dir(self.config.temp_dir.as_ref().map(PathBuf::from))
+    }
+
+    /// Файл, который только читается: временный файл не создаётся
+    fn reader_service(&self, path: impl Into<PathBuf>) -> LocalFileService {
+        LocalFileService::new(self.config.chunk_size, path)
+    }
+
+    /// Читает данные из stdin и пишет результат в stdout
+    fn stream_service(
+        &self,
+    ) -> StreamFileService<std::io::StdinLock<'static>, std::io::StdoutLock<'static>> {
+        StreamFileService::stdio(self.config.chunk_size)
+    }
+
+    /// Пишет результат в файл `--output`, не трогая исходный
+    fn output_service(&self, path: PathBuf, output: &str) -> OutputFileService {
+        OutputFileService::new(self.file_service(path), output, self.options.force)
     }
 
-    fn file_service(&self, path: String) -> LocalFileService {
-        let path_buf = PathBuf::from(path);
-        LocalFileService::new(self.config.chunk_size, path_buf)
+    /// Предупреждает один раз за запуск, если временные файлы `decrypt`
+    /// пишутся на другой постоянный носитель: расшифрованные данные могут
+    /// остаться на нём после копирования и удаления. Остальные команды, в том
+    /// числе `rekey`, пишут во временный файл только зашифрованные данные.
+    fn warn_persistent_temp(&self, path: &Path) {
+        let Some(temp_dir) = &self.config.temp_dir else {
+            return;
+        };
+        if self.temp_warned.get() {
+            return;
+        }
+        let path = self.options.output.as_deref().map_or(path, Path::new);
+        let original_dir = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+
+        let same_fs = same_filesystem(Path::new(temp_dir), original_dir).unwrap_or(false);
+        if !same_fs && !is_volatile_dir(Path::new(temp_dir)) {
+            self.temp_warned.set(true);
+            Terminal.print_error_msg(format!(
+                "Предупреждение: временные файлы записываются в {temp_dir} на другой файловой системе, которая не находится в оперативной памяти; расшифрованные данные могут остаться на ней"
+            ));
+        }
     }
 
-    fn crypto_service(&self) -> AesCtrCryptoService {
-        AesCtrCryptoService::from(self.config.clone())
+    fn crypto_service(&self) -> AutoCryptoService {
+        AutoCryptoService::from(self.config.clone())
     }
 
     pub fn run_command(&self, command: Command) -> Result<(), CommandError<FsError, CryptoError>> {
         match command {
-            Command::Prepare(path) => {
-                PrepareUseCase::new(self.file_service(path), self.crypto_service(), Terminal)
+            Command::Prepare(path) => self.walk(
+                PathBuf::from(path),
+                self.options.recursive,
+                Self::prepare_file,
+            ),
+            Command::Decrypt(path) => self.walk(
+                PathBuf::from(path),
+                self.options.recursive,
+                Self::decrypt_file,
+            ),
+            Command::Read(path) if path == STDIO_PATH => {
+                ReadUseCase::new(self.stream_service(), self.crypto_service(), Terminal)
+                    .with_cancel(self.cancel.clone())
+                    .with_format(self.options.read_format)
                     .execute()
             }
-            Command::Decrypt(path) => {
-                DecryptUseCase::new(self.file_service(path), self.crypto_service(), Terminal)
+            Command::Read(path) => {
+                ReadUseCase::new(self.reader_service(path), self.crypto_service(), Terminal)
+                    .with_cancel(self.cancel.clone())
+                    .with_format(self.options.read_format)
                     .execute()
             }
-            Command::Read(path) => {
-                ReadUseCase::new(self.file_service(path), self.crypto_service(), Terminal).execute()
+            Command::Rekey(path) => self.walk(PathBuf::from(path), true, Self::rekey_file),
+            Command::Keygen(options, path) => Self::keygen(options, PathBuf::from(path)),
+            Command::Recipients(action, path) => self.recipients(action, path),
+            Command::Recover(path) => self.recover(PathBuf::from(path)),
+            Command::ShowConfig => Self::show_config(&self.options),
+        }
+    }
+
+    /// Выводит действующие настройки; работает и без ключа, чтобы можно было
+    /// выяснить, почему настройки не загружаются
+    pub fn show_config(options: &CliOptions) -> Result<(), ExecutorError> {
+        let lines = ConfigSources::discover(options)
+            .and_then(|sources| sources.describe(options))
+            .map_err(|e| CommandError::Config(e.consol_log()))?;
+
+        for line in lines {
+            Terminal.print_msg(line);
+        }
+        Ok(())
+    }
+
+    fn header_rewriter(&self) -> HeaderRewriter {
+        HeaderRewriter::from(KeyResolver::from(self.config.clone()))
+    }
+
+    fn recipients(
+        &self,
+        action: RecipientsAction,
+        path: String,
+    ) -> Result<(), CommandError<FsError, CryptoError>> {
+        let edit = match action {
+            RecipientsAction::Add(recipients) => HeaderEdit::AddRecipients(recipients),
+            RecipientsAction::Remove(recipients) => HeaderEdit::RemoveRecipients(recipients),
+            RecipientsAction::List => {
+                return ListRecipientsUseCase::new(
+                    self.reader_service(path),
+                    self.header_rewriter(),
+                    Terminal,
+                )
+                .execute();
             }
+        };
+
+        RewriteHeaderUseCase::new(
+            self.file_service(path),
+            self.header_rewriter().with_edit(edit),
+            Terminal,
+        )
+        .with_cancel(self.cancel.clone())
+        .execute()
+    }
+
+    /// Создаёт файл identity X25519 и выводит открытый ключ для `--recipient`
+    /// или, с флагом `--symmetric`, симметричный ключ. Настройки для этого не нужны.
+    pub fn keygen(
+        options: KeygenOptions,
+        path: PathBuf,
+    ) -> Result<(), CommandError<FsError, CryptoError>> {
+        if options.symmetric {
+            return Self::keygen_symmetric(options, path);
+        }
+
+        let identity = Identity::generate();
+
+        write_secret_file(&path, identity.to_file().as_bytes())
+            .map_err(CommandError::FileServiceError)?;
+
+        Terminal.print_msg(identity.recipient().to_string());
+        Ok(())
+    }
+
+    /// Записывает ключ в новый файл настроек, в связку существующего файла
+    /// (нужно `--name`) или, в формате `raw`, в отдельный файл ключа
+    fn keygen_symmetric(
+        options: KeygenOptions,
+        path: PathBuf,
+    ) -> Result<(), CommandError<FsError, CryptoError>> {
+        let suite = match &options.suite {
+            Some(name) => CipherSuite::from_name(name).map_err(CommandError::CryptoService)?,
+            None => CipherSuite::Aes256GcmStream,
+        };
+        if let Some(name) = &options.name {
+            validate_name(name).map_err(CommandError::CryptoService)?;
         }
+        let key = generate_key(suite);
+
+        if options.format == KeyFormat::Raw {
+            write_secret_file(&path, &key).map_err(CommandError::FileServiceError)?;
+            Terminal.print_msg(format!("Ключ записан в {}", path.display()));
+            return Ok(());
+        }
+
+        let value = encode_key(&key, options.format);
+        match (path.exists(), options.name.as_deref()) {
+            (false, name) => write_secret_file(&path, new_credentials(name, &value).as_bytes())
+                .map_err(CommandError::FileServiceError)?,
+            (true, Some(name)) => {
+                let content = std::fs::read_to_string(&path)
+                    .map_err(|e| CommandError::FileServiceError(FsError::Io(e)))?;
+                let updated =
+                    add_to_keyring(&content, name, &value).map_err(CommandError::CryptoService)?;
+                replace_secret_file(&path, updated.as_bytes())
+                    .map_err(CommandError::FileServiceError)?;
+            }
+            (true, None) => {
+                return Err(CommandError::CryptoService(CryptoError::InvalidKey(
+                    format!(
+                        "файл {} уже существует, укажите --name, чтобы добавить ключ в связку",
+                        path.display()
+                    ),
+                )));
+            }
+        }
+
+        Terminal.print_msg(format!("Ключ записан в {}", path.display()));
+        Ok(())
+    }
+
+    /// Обрабатывает файл или, если разрешено `recursive`, все отобранные
+    /// файлы каталога. Ошибка в одном файле не останавливает обработку остальных.
+    ///
+    /// `process` возвращает `true`, если файл пропущен.
+    fn walk(
+        &self,
+        path: PathBuf,
+        recursive: bool,
+        process: fn(&Self, PathBuf) -> Result<bool, ExecutorError>,
+    ) -> Result<(), ExecutorError> {
+        if !path.is_dir() {
+            return process(self, path).map(|_| ());
+        }
+        if self.options.output.is_some() {
+            return Err(CommandError::FileServiceError(FsError::Io(
+                std::io::Error::other(format!(
+                    "{} является каталогом, --output применим только к файлу",
+                    path.display()
+                )),
+            )));
+        }
+        if !recursive {
+            return Err(CommandError::FileServiceError(FsError::Io(
+                std::io::Error::other(format!("{} является каталогом, укажите -r", path.display())),
+            )));
+        }
+
+        let files = FileWalker::new(&self.options.include, &self.options.exclude)
+            .map_err(|e| std::io::Error::other(e.to_string()))
+            .and_then(|walker| walker.collect(&path))
+            .map_err(|e| CommandError::FileServiceError(FsError::Io(e)))?;
+
+        let (mut processed, mut skipped, mut failed) = (0, 0, 0);
+        let mut first_error = None;
+        for file in files {
+            if self.cancel.is_cancelled() {
+                first_error = Some(CommandError::Cancelled);
+                break;
+            }
+
+            Terminal.print_msg(file.display().to_string());
+            match process(self, file.clone()) {
+                Ok(true) => skipped += 1,
+                Ok(false) => processed += 1,
+                Err(CommandError::Cancelled) => {
+                    first_error = Some(CommandError::Cancelled);
+                    break;
+                }
+                Err(e) => {
+                    failed += 1;
+                    Terminal.print_error_msg(format!("{}: {}", file.display(), e.consol_log()));
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+
+        Terminal.print_msg(format!(
+            "Обработано: {processed}, пропущено: {skipped}, ошибок: {failed}"
+        ));
+        first_error.map_or(Ok(()), Err)
+    }
+
+    /// Находит временные файлы прерванных операций, проверяет каждый
+    /// и заменяет им исходный, удаляет или сохраняет рядом
+    fn recover(&self, dir: PathBuf) -> Result<(), ExecutorError> {
+        let mut orphans = self
+            .find_orphans(&dir)
+            .map_err(|e| CommandError::FileServiceError(FsError::Io(e)))?;
+
+        // Временные файлы из temp_dir, исходные файлы которых лежат в каталоге
+        if let Some(temp_dir) = &self.config.temp_dir
+            && Path::new(temp_dir).is_dir()
+        {
+            let dir = std::path::absolute(&dir)
+                .map_err(|e| CommandError::FileServiceError(FsError::Io(e)))?;
+            let from_temp_dir = self
+                .find_orphans(Path::new(temp_dir))
+                .map_err(|e| CommandError::FileServiceError(FsError::Io(e)))?;
+            for orphan in from_temp_dir {
+                if orphan.original.starts_with(&dir) && !orphans.contains(&orphan) {
+                    orphans.push(orphan);
+                }
+            }
+        }
+
+        if orphans.is_empty() {
+            Terminal.print_msg("Временные файлы не найдены".to_string());
+            return Ok(());
+        }
+
+        let (mut replaced, mut deleted, mut kept, mut skipped, mut failed) = (0, 0, 0, 0, 0);
+        let mut first_error = None;
+        for orphan in orphans {
+            if self.cancel.is_cancelled() {
+                first_error = Some(CommandError::Cancelled);
+                break;
+            }
+
+            let status = self.inspect(&orphan);
+            let original_exists = orphan.original_exists();
+            Terminal.print_msg(format!(
+                "{}: {}",
+                orphan.path.display(),
+                match status {
+                    TempStatus::Complete => "записан полностью",
+                    TempStatus::Partial => "запись оборвалась",
+                    TempStatus::Unknown => "проверить не удалось",
+                }
+            ));
+            Terminal.print_msg(match original_exists {
+                true => format!("  исходный файл: {}", orphan.original.display()),
+                false => format!("  исходный файл {} отсутствует", orphan.original.display()),
+            });
+
+            let mut action =
+                self.recover_action(RecoverAction::recommended(status, original_exists));
+            if self.cancel.is_cancelled() {
+                action = RecoverAction::Skip;
+            }
+            let result = match action {
+                RecoverAction::Replace => orphan.replace(self.options.preserve).map(|_| {
+                    replaced += 1;
+                    format!("  заменён исходный файл {}", orphan.original.display())
+                }),
+                RecoverAction::Delete => orphan.delete().map(|_| {
+                    deleted += 1;
+                    "  временный файл удалён".to_string()
+                }),
+                RecoverAction::Keep => orphan.keep().map(|kept_path| {
+                    kept += 1;
+                    format!("  временный файл сохранён как {}", kept_path.display())
+                }),
+                RecoverAction::Skip => {
+                    skipped += 1;
+                    Ok("  оставлен без изменений".to_string())
+                }
+            };
+
+            match result {
+                Ok(msg) => Terminal.print_msg(msg),
+                Err(e) => {
+                    failed += 1;
+                    let e = CommandError::FileServiceError(FsError::Io(e));
+                    Terminal.print_error_msg(format!("  {}", e.consol_log()));
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+
+        Terminal.print_msg(format!(
+            "Заменено: {replaced}, удалено: {deleted}, сохранено рядом: {kept}, без изменений: {skipped}, ошибок: {failed}"
+        ));
+        first_error.map_or(Ok(()), Err)
+    }
+
+    fn find_orphans(&self, dir: &Path) -> std::io::Result<Vec<OrphanTemp>> {
+        let paths = FileWalker::new(&["*.tmp".into()], &self.options.exclude)
+            .map_err(|e| std::io::Error::other(e.to_string()))?
+            .collect(dir)?;
+        Ok(paths
+            .iter()
+            .filter_map(|path| OrphanTemp::from_path(path))
+            .collect())
+    }
+
+    /// Ошибка проверки целостности означает, что запись оборвалась;
+    /// другие ошибки, например неверный ключ, не позволяют судить о файле
+    fn inspect(&self, orphan: &OrphanTemp) -> TempStatus {
+        let original = orphan
+            .original_exists()
+            .then(|| self.reader_service(&orphan.original));
+        let mut use_case = InspectTempUseCase::new(
+            self.reader_service(&orphan.path),
+            original,
+            self.crypto_service(),
+            self.crypto_service(),
+            Terminal,
+        );
+
+        match use_case.execute() {
+            Ok(()) => use_case.status(),
+            Err(CommandError::CryptoService(
+                CryptoError::AuthenticationFailed | CryptoError::InvalidHeader(_),
+            )) => TempStatus::Partial,
+            Err(e) => {
+                Terminal.print_error_msg(format!("  {}", e.consol_log()));
+                TempStatus::Unknown
+            }
+        }
+    }
+
+    /// Спрашивает действие в терминале. С `--yes` выполняется рекомендуемое,
+    /// а без терминала файл остаётся без изменений.
+    fn recover_action(&self, recommended: RecoverAction) -> RecoverAction {
+        if self.options.assume_yes {
+            return recommended;
+        }
+
+        let default = match recommended {
+            RecoverAction::Replace => "з",
+            RecoverAction::Delete => "у",
+            RecoverAction::Keep => "о",
+            RecoverAction::Skip => "п",
+        };
+        loop {
+            let Some(answer) = Terminal.ask(format!(
+                "[з]аменить исходный, [у]далить, [о]ставить оба, [п]ропустить? [{default}]"
+            )) else {
+                return RecoverAction::Skip;
+            };
+
+            match answer.as_str() {
+                "" => return recommended,
+                "з" | "r" => return RecoverAction::Replace,
+                "у" | "d" => return RecoverAction::Delete,
+                "о" | "k" => return RecoverAction::Keep,
+                "п" | "s" => return RecoverAction::Skip,
+                _ => Terminal.print_error_msg("Неизвестный ответ".to_string()),
+            }
+        }
+    }
+
+    /// Политика `--on-already` для файла. В канале пропуск означал бы
+    /// пустой вывод, поэтому вместо него возвращается ошибка.
+    fn on_already(&self, path: &Path) -> AlreadyProcessed {
+        match self.options.on_already {
+            AlreadyProcessed::Skip if path == Path::new(STDIO_PATH) => AlreadyProcessed::Error,
+            policy => policy,
+        }
+    }
+
+    fn prepare_file(&self, path: PathBuf) -> Result<bool, ExecutorError> {
+        if path == Path::new(STDIO_PATH) {
+            return self.prepare_with(self.stream_service(), PipeTerminal, &path);
+        }
+        match &self.options.output {
+            Some(output) => {
+                self.prepare_with(self.output_service(path.clone(), output), Terminal, &path)
+            }
+            None => self.prepare_with(self.file_service(&path), Terminal, &path),
+        }
+    }
+
+    fn prepare_with<F, T>(
+        &self,
+        file_service: F,
+        terminal: T,
+        path: &Path,
+    ) -> Result<bool, ExecutorError>
+    where
+        F: FileService<Error = FsError>,
+        T: TerminalService,
+    {
+        let mut use_case = PrepareUseCase::new(file_service, self.crypto_service(), terminal)
+            .on_already(self.on_already(path))
+            .with_cancel(self.cancel.clone());
+        use_case.execute()?;
+        Ok(use_case.is_skipped())
+    }
+
+    fn decrypt_file(&self, path: PathBuf) -> Result<bool, ExecutorError> {
+        if path == Path::new(STDIO_PATH) {
+            return self.decrypt_with(self.stream_service(), PipeTerminal, &path);
+        }
+        self.warn_persistent_temp(&path);
+        match &self.options.output {
+            Some(output) => {
+                self.decrypt_with(self.output_service(path.clone(), output), Terminal, &path)
+            }
+            None => self.decrypt_with(self.file_service(&path), Terminal, &path),
+        }
+    }
+
+    fn decrypt_with<F, T>(
+        &self,
+        file_service: F,
+        terminal: T,
+        path: &Path,
+    ) -> Result<bool, ExecutorError>
+    where
+        F: FileService<Error = FsError>,
+        T: TerminalService,
+    {
+        // Результат в другом файле или в канале не заменяет исходный файл
+        let allow_legacy = self.options.force_legacy
+            || self.options.output.is_some()
+            || path == Path::new(STDIO_PATH);
+        let mut use_case = DecryptUseCase::new(file_service, self.crypto_service(), terminal)
+            .on_already(self.on_already(path))
+            .allow_legacy(allow_legacy)
+            .with_cancel(self.cancel.clone());
+        use_case.execute()?;
+        Ok(use_case.is_skipped())
+    }
+
+    fn rekey_file(&self, path: PathBuf) -> Result<bool, ExecutorError> {
+        if path == Path::new(STDIO_PATH) {
+            // Результат в канале не заменяет исходный файл
+            return self.rekey_with(self.stream_service(), PipeTerminal, true);
+        }
+        self.rekey_with(self.file_service(path), Terminal, self.options.force_legacy)
+    }
+
+    fn rekey_with<F, T>(
+        &self,
+        file_service: F,
+        terminal: T,
+        allow_legacy: bool,
+    ) -> Result<bool, ExecutorError>
+    where
+        F: FileService<Error = FsError>,
+        T: TerminalService,
+    {
+        let target = self.rekey_target.as_ref().unwrap_or(&self.config);
+        let mut use_case = RekeyUseCase::new(
+            file_service,
+            self.crypto_service(),
+            AutoCryptoService::from(target.clone()),
+            self.header_rewriter()
+                .with_target(KeyResolver::from(target.clone())),
+            terminal,
+        )
+        .full(self.options.full_rekey)
+        .allow_legacy(allow_legacy)
+        .with_cancel(self.cancel.clone());
+        use_case.execute()?;
+        Ok(use_case.is_skipped())
     }
 }
@@ -0,0 +1,147 @@
+use std::path::{Path, PathBuf};
+
+use glob::{MatchOptions, Pattern, PatternError};
+
+/// Файл в корне каталога со списком шаблонов исключений
+pub const IGNORE_FILE: &str = ".cryptoignore";
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Шаблон без `/` сравнивается с именем файла, иначе с путём от корня обхода
+fn matches(pattern: &Pattern, relative: &Path) -> bool {
+    if pattern.as_str().contains('/') {
+        pattern.matches_path_with(relative, MATCH_OPTIONS)
+    } else {
+        relative
+            .file_name()
+            .is_some_and(|name| pattern.matches_with(&name.to_string_lossy(), MATCH_OPTIONS))
+    }
+}
+
+/// Отбирает файлы каталога по шаблонам `--include`, `--exclude` и `.cryptoignore`
+pub struct FileWalker {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl FileWalker {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self, PatternError> {
+        Ok(Self {
+            include: include
+                .iter()
+                .map(|p| Pattern::new(p))
+                .collect::<Result<_, _>>()?,
+            exclude: exclude
+                .iter()
+                .map(|p| Pattern::new(p))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Рекурсивно собирает обычные файлы каталога в порядке путей.
+    /// Символические ссылки пропускаются; исключённый каталог не обходится.
+    pub fn collect(mut self, root: &Path) -> std::io::Result<Vec<PathBuf>> {
+        let ignore_path = root.join(IGNORE_FILE);
+        if ignore_path.is_file() {
+            for line in std::fs::read_to_string(&ignore_path)?.lines() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let pattern = Pattern::new(line.trim_end_matches('/')).map_err(|e| {
+                    std::io::Error::other(format!("{}: {e}", ignore_path.display()))
+                })?;
+                self.exclude.push(pattern);
+            }
+        }
+
+        let mut files = Vec::new();
+        self.collect_dir(root, root, &mut files)?;
+        Ok(files)
+    }
+
+    fn collect_dir(
+        &self,
+        root: &Path,
+        dir: &Path,
+        files: &mut Vec<PathBuf>,
+    ) -> std::io::Result<()> {
+        let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.path());
+
+        for entry in entries {
+            let path = entry.path();
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+            if self.exclude.iter().any(|p| matches(p, relative)) {
+                continue;
+            }
+
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                self.collect_dir(root, &path, files)?;
+            } else if file_type.is_file() && self.is_included(relative) {
+                files.push(path);
+            }
+        }
+        Ok(())
+    }
+
+    fn is_included(&self, relative: &Path) -> bool {
+        if relative == Path::new(IGNORE_FILE) {
+            return false;
+        }
+        self.include.is_empty() || self.include.iter().any(|p| matches(p, relative))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn make_tree(name: &str) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("crypto_files_{name}_{}", uuid::Uuid::new_v4()));
+        for file in ["a.txt", "b.key", "logs/c.txt", "logs/d.log", "keep/e.txt"] {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"data").unwrap();
+        }
+        root
+    }
+
+    fn relative(root: &Path, files: Vec<PathBuf>) -> Vec<String> {
+        files
+            .iter()
+            .map(|f| f.strip_prefix(root).unwrap().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_include_exclude() {
+        let root = make_tree("include");
+
+        let walker = FileWalker::new(&["*.txt".into()], &["logs".into()]).unwrap();
+        let files = relative(&root, walker.collect(&root).unwrap());
+
+        assert_eq!(files, vec!["a.txt", "keep/e.txt"]);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    //Проверяем, что шаблоны из .cryptoignore исключают файлы, а сам файл не обрабатывается
+    #[test]
+    fn test_cryptoignore() {
+        let root = make_tree("ignore");
+        fs::write(root.join(IGNORE_FILE), "# comment\n*.log\nkeep/\n").unwrap();
+
+        let walker = FileWalker::new(&[], &[]).unwrap();
+        let files = relative(&root, walker.collect(&root).unwrap());
+
+        assert_eq!(files, vec!["a.txt", "b.key", "logs/c.txt"]);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
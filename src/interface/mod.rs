@@ -1,3 +1,5 @@
 pub mod command_factory;
+pub mod config_sources;
 pub mod credentials_loader;
 pub mod executor;
+pub mod file_walker;
@@ -3,29 +3,62 @@ mod domain;
 mod infrastructure;
 mod interface;
 
+use domain::models::{CancelToken, Command, CommandError};
 use domain::services::ConsoleError;
 use interface::command_factory::CommandFactory;
 use interface::credentials_loader::CredentialsLoader;
 use interface::executor::CommandExecutor;
 
+/// Код выхода после SIGINT/SIGTERM, как у процессов, завершённых SIGINT
+const EXIT_INTERRUPTED: i32 = 130;
+
 fn main() {
     let args: Vec<String> = std::env::args().collect();
 
-    let credentials = CredentialsLoader::try_load().unwrap_or_else(|e| {
-        eprintln!("Ошибка получения credentials: {}", e.consol_log());
-        std::process::exit(1);
-    });
-
-    let command = CommandFactory::from_args(&args).unwrap_or_else(|e| {
+    let (command, options) = CommandFactory::from_args(&args).unwrap_or_else(|e| {
         eprintln!("Ошибка распознавания команды {e}");
         std::process::exit(1);
     });
 
-    let executor = CommandExecutor::new(credentials);
+    let result = match command {
+        Command::Keygen(keygen, path) => CommandExecutor::keygen(keygen, path.into()),
+        Command::ShowConfig => CommandExecutor::show_config(&options),
+        command => {
+            let confirm = matches!(command, Command::Prepare(_));
+            let loaded = match command {
+                Command::Rekey(_) => CredentialsLoader::try_load_rekey(&options)
+                    .map(|(source, target)| (source, Some(target))),
+                _ => CredentialsLoader::try_load(&options, confirm).map(|creds| (creds, None)),
+            };
+            let (credentials, rekey_target) = loaded.unwrap_or_else(|e| {
+                eprintln!("Ошибка получения credentials: {}", e.consol_log());
+                std::process::exit(1);
+            });
 
-    executor.run_command(command).unwrap_or_else(|e| {
+            // Обработчик ставится после ввода пароля, чтобы Ctrl-C
+            // во время ввода по-прежнему завершал программу
+            let cancel = CancelToken::default();
+            let handler_token = cancel.clone();
+            ctrlc::set_handler(move || handler_token.cancel()).unwrap_or_else(|e| {
+                eprintln!("Не удалось установить обработчик сигналов: {e}");
+                std::process::exit(1);
+            });
+
+            let mut executor = CommandExecutor::new(credentials, options);
+            if let Some(target) = rekey_target {
+                executor = executor.with_rekey_target(target);
+            }
+            executor.with_cancel(cancel).run_command(command)
+        }
+    };
+
+    result.unwrap_or_else(|e| {
         eprintln!("Ошибка: {}", e.consol_log());
-        std::process::exit(1);
+        let code = match e {
+            CommandError::Cancelled => EXIT_INTERRUPTED,
+            _ => 1,
+        };
+        std::process::exit(code);
     });
     std::process::exit(0);
 }